@@ -1,16 +1,30 @@
+pub mod forwarding;
+pub mod header;
 pub mod proxy;
+pub mod redirect;
+pub mod respond;
+pub mod response;
+pub mod serve_dir;
+pub mod upstream;
 
 use crate::action::proxy::Proxy;
+use crate::action::redirect::Redirect;
+use crate::action::respond::Respond;
+use crate::action::serve_dir::ServeDir;
 use http::Request;
 
 pub enum Action {
-    Proxy(Proxy)
+    Proxy(Box<Proxy>),
+    Redirect(Redirect),
+    Respond(Respond),
+    ServeDir(ServeDir),
 }
 
 impl Action {
     pub fn transform_req<T>(&self, req: Request<T>) -> Option<Request<T>> {
         let builder = match self {
             Self::Proxy(proxy) => proxy.transform_req(&req),
+            Self::Redirect(_) | Self::Respond(_) | Self::ServeDir(_) => None,
         };
 
         builder?.body(req.into_body()).ok()
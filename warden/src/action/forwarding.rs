@@ -0,0 +1,476 @@
+use crate::conn::{client_scheme, ClientAddr, TlsInfo};
+use crate::trigger::host::authority;
+use http::header::{
+    HeaderName, HeaderValue, CONNECTION, FORWARDED, HOST, TE, TRAILER, TRANSFER_ENCODING, UPGRADE,
+};
+use http::{HeaderMap, Request};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+
+/// Remove hop-by-hop headers, which apply to a single connection (RFC 9110 section 7.6.1),
+/// including any named in `Connection`.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let named: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_str(name.trim()).ok())
+        .collect();
+
+    for name in named {
+        headers.remove(name);
+    }
+
+    for name in [CONNECTION, TE, TRAILER, TRANSFER_ENCODING, UPGRADE] {
+        headers.remove(name);
+    }
+
+    for name in ["keep-alive", "proxy-connection"] {
+        headers.remove(name);
+    }
+}
+
+/// A network of addresses, such as `10.0.0.0/8` or a single address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Whether `ip` is in the network. IPv4-mapped IPv6 addresses are in IPv4 networks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(u32::from(net).into(), u32::from(ip).into(), self.prefix, 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// `ip`, with IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`, as dual-stack listeners see IPv4
+/// clients) converted to IPv4.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, prefix: u8, bits: u8) -> bool {
+    let shift = u32::from(bits - prefix);
+    shift >= u32::from(bits) || (net >> shift) == (ip >> shift)
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr = IpAddr::from_str(addr).map_err(|e| e.to_string())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid prefix length {}", p))?,
+            None => max,
+        };
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// Headers carrying the verified client certificate identity upstream. Copies sent by the client
+/// are always removed, so upstreams can trust them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientCertHeaders {
+    /// Header for the subject distinguished name.
+    pub subject: Option<HeaderName>,
+    /// Header for the subject alternative names, comma separated.
+    pub san: Option<HeaderName>,
+    /// Header for the SHA-256 fingerprint.
+    pub fingerprint: Option<HeaderName>,
+}
+
+impl ClientCertHeaders {
+    fn apply<T>(&self, req: &Request<T>, headers: &mut HeaderMap) {
+        let cert = req
+            .extensions()
+            .get::<TlsInfo>()
+            .and_then(|info| info.client_cert.as_ref());
+
+        let fields = [
+            (&self.subject, cert.map(|c| c.subject.clone())),
+            (&self.san, cert.map(|c| c.sans.join(", "))),
+            (&self.fingerprint, cert.map(|c| c.fingerprint.clone())),
+        ];
+
+        for (name, value) in fields {
+            let name = match name {
+                Some(name) => name,
+                None => continue,
+            };
+
+            headers.remove(name);
+            if let Some(value) = value.and_then(|v| HeaderValue::from_str(&v).ok()) {
+                headers.insert(name, value);
+            }
+        }
+    }
+}
+
+/// How requests are presented to upstreams: the `Host` sent, and the client information added.
+pub struct Forwarding {
+    /// Proxies whose `X-Forwarded-*` and `Forwarded` headers are extended rather than replaced.
+    pub trusted_proxies: Vec<Cidr>,
+    /// Send the client's `Host` rather than the upstream's authority.
+    pub preserve_host: bool,
+    /// Add `X-Forwarded-For`, `X-Forwarded-Proto`, `X-Forwarded-Host` and `Forwarded`.
+    pub headers: bool,
+    pub client_cert: ClientCertHeaders,
+}
+
+impl Default for Forwarding {
+    fn default() -> Self {
+        Self {
+            trusted_proxies: Vec::new(),
+            preserve_host: false,
+            headers: true,
+            client_cert: ClientCertHeaders::default(),
+        }
+    }
+}
+
+impl Forwarding {
+    /// Update the headers copied from `req` for sending upstream.
+    pub fn apply<T>(&self, req: &Request<T>, headers: &mut HeaderMap) {
+        strip_hop_by_hop(headers);
+
+        if !self.preserve_host {
+            // The client sets `Host` from the upstream URI.
+            headers.remove(HOST);
+        } else if !headers.contains_key(HOST) {
+            // HTTP/2 clients send the authority in the URI rather than `Host`.
+            let host = authority(req).and_then(|a| HeaderValue::from_str(a.as_str()).ok());
+            if let Some(host) = host {
+                headers.insert(HOST, host);
+            }
+        }
+
+        self.client_cert.apply(req, headers);
+
+        if !self.headers {
+            return;
+        }
+
+        let peer = req
+            .extensions()
+            .get::<ClientAddr>()
+            .map(|addr| canonical(addr.0.ip()));
+        let trusted =
+            peer.is_some_and(|ip| self.trusted_proxies.iter().any(|net| net.contains(ip)));
+
+        if !trusted {
+            for name in [X_FORWARDED_FOR, X_FORWARDED_PROTO, X_FORWARDED_HOST] {
+                headers.remove(name);
+            }
+            headers.remove(FORWARDED);
+        }
+
+        let proto = client_scheme(req);
+        let host = authority(req).map(|a| a.to_string());
+
+        if let Some(peer) = peer {
+            let forwarded_for = match headers.get(X_FORWARDED_FOR).and_then(|v| v.to_str().ok()) {
+                Some(existing) => format!("{}, {}", existing, peer),
+                None => peer.to_string(),
+            };
+            insert(headers, X_FORWARDED_FOR, &forwarded_for);
+        }
+
+        if !headers.contains_key(X_FORWARDED_PROTO) {
+            insert(headers, X_FORWARDED_PROTO, proto);
+        }
+
+        if let Some(host) = &host {
+            if !headers.contains_key(X_FORWARDED_HOST) {
+                insert(headers, X_FORWARDED_HOST, host);
+            }
+        }
+
+        let mut element = Vec::new();
+        if let Some(peer) = peer {
+            element.push(format!("for={}", forwarded_node(peer)));
+        }
+        if let Some(host) = &host {
+            element.push(format!("host={}", forwarded_value(host)));
+        }
+        element.push(format!("proto={}", proto));
+
+        let forwarded = match headers.get(FORWARDED).and_then(|v| v.to_str().ok()) {
+            Some(existing) => format!("{}, {}", existing, element.join(";")),
+            None => element.join(";"),
+        };
+        insert(headers, FORWARDED.as_str(), &forwarded);
+    }
+}
+
+fn insert(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(HeaderName::from_static(name), value);
+    }
+}
+
+/// A node for the `Forwarded` header; IPv6 addresses are bracketed and quoted (RFC 7239).
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    }
+}
+
+/// A `Forwarded` value, quoted unless it is a token.
+fn forwarded_value(value: &str) -> String {
+    let is_token = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+
+    if is_token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn mk_req(peer: [u8; 4], headers: &[(&str, &str)]) -> Request<()> {
+        let mut builder = Request::builder()
+            .uri("/x")
+            .header(HOST, "example.com:8080");
+        for (k, v) in headers {
+            builder = builder.header(*k, *v);
+        }
+
+        let mut req = builder.body(()).unwrap();
+        req.extensions_mut()
+            .insert(ClientAddr(SocketAddr::from((peer, 4000))));
+        req
+    }
+
+    fn forwarded(forwarding: &Forwarding, req: &Request<()>) -> HeaderMap {
+        let mut headers = req.headers().clone();
+        forwarding.apply(req, &mut headers);
+        headers
+    }
+
+    #[test]
+    fn hop_by_hop() {
+        let mut headers = HeaderMap::new();
+        for (k, v) in [
+            ("connection", "keep-alive, x-hop"),
+            ("keep-alive", "timeout=5"),
+            ("transfer-encoding", "chunked"),
+            ("upgrade", "h2c"),
+            ("te", "trailers"),
+            ("x-hop", "1"),
+            ("x-end-to-end", "1"),
+        ] {
+            headers.insert(HeaderName::from_static(k), HeaderValue::from_static(v));
+        }
+
+        strip_hop_by_hop(&mut headers);
+        assert_eq!(
+            headers.keys().map(|k| k.as_str()).collect::<Vec<_>>(),
+            vec!["x-end-to-end"],
+            "Hop-by-hop headers, and those named in Connection, are removed"
+        );
+    }
+
+    #[test]
+    fn cidrs() {
+        let net = Cidr::from_str("10.1.0.0/16").unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+
+        let v6 = Cidr::from_str("fd00::/8").unwrap();
+        assert!(v6.contains("fd12::1".parse().unwrap()));
+        assert!(!v6.contains("10.1.2.3".parse().unwrap()));
+
+        let any = Cidr::from_str("0.0.0.0/0").unwrap();
+        assert!(any.contains("192.0.2.1".parse().unwrap()));
+
+        let single = Cidr::from_str("192.0.2.1").unwrap();
+        assert!(single.contains("192.0.2.1".parse().unwrap()));
+        assert!(!single.contains("192.0.2.2".parse().unwrap()));
+
+        assert!(Cidr::from_str("10.0.0.0/33").is_err());
+
+        assert!(
+            net.contains("::ffff:10.1.2.3".parse().unwrap()),
+            "IPv4-mapped addresses are in IPv4 networks"
+        );
+        assert!(!net.contains("::ffff:10.2.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn dual_stack_peer() {
+        let mut req = mk_req([0, 0, 0, 0], &[("x-forwarded-for", "203.0.113.9")]);
+        let mapped: IpAddr = "::ffff:10.0.0.5".parse().unwrap();
+        req.extensions_mut()
+            .insert(ClientAddr(SocketAddr::new(mapped, 4000)));
+        let forwarding = Forwarding {
+            trusted_proxies: vec![Cidr::from_str("10.0.0.0/8").unwrap()],
+            ..Forwarding::default()
+        };
+        let headers = forwarded(&forwarding, &req);
+
+        assert_eq!(
+            headers[X_FORWARDED_FOR], "203.0.113.9, 10.0.0.5",
+            "IPv4 clients of dual-stack listeners are trusted and recorded as IPv4"
+        );
+        assert_eq!(
+            headers[FORWARDED],
+            "for=10.0.0.5;host=\"example.com:8080\";proto=http"
+        );
+    }
+
+    #[test]
+    fn untrusted_client() {
+        let req = mk_req(
+            [192, 0, 2, 1],
+            &[("x-forwarded-for", "1.1.1.1"), ("forwarded", "for=1.1.1.1")],
+        );
+        let headers = forwarded(&Forwarding::default(), &req);
+
+        assert!(
+            !headers.contains_key(HOST),
+            "Host is not preserved by default"
+        );
+        assert_eq!(
+            headers[X_FORWARDED_FOR], "192.0.2.1",
+            "Headers from untrusted clients are replaced"
+        );
+        assert_eq!(headers[X_FORWARDED_PROTO], "http");
+        assert_eq!(headers[X_FORWARDED_HOST], "example.com:8080");
+        assert_eq!(
+            headers[FORWARDED],
+            "for=192.0.2.1;host=\"example.com:8080\";proto=http"
+        );
+    }
+
+    #[test]
+    fn trusted_proxy() {
+        let req = mk_req(
+            [10, 0, 0, 5],
+            &[
+                ("x-forwarded-for", "203.0.113.9"),
+                ("x-forwarded-proto", "https"),
+                ("forwarded", "for=203.0.113.9;proto=https"),
+            ],
+        );
+        let forwarding = Forwarding {
+            trusted_proxies: vec![Cidr::from_str("10.0.0.0/8").unwrap()],
+            preserve_host: true,
+            ..Forwarding::default()
+        };
+        let headers = forwarded(&forwarding, &req);
+
+        assert_eq!(headers[HOST], "example.com:8080", "Host can be preserved");
+        assert_eq!(
+            headers[X_FORWARDED_FOR], "203.0.113.9, 10.0.0.5",
+            "Headers from trusted proxies are extended"
+        );
+        assert_eq!(headers[X_FORWARDED_PROTO], "https");
+        assert_eq!(
+            headers[FORWARDED],
+            "for=203.0.113.9;proto=https, for=10.0.0.5;host=\"example.com:8080\";proto=http"
+        );
+    }
+
+    #[test]
+    fn disabled() {
+        let req = mk_req([192, 0, 2, 1], &[("connection", "close")]);
+        let forwarding = Forwarding {
+            headers: false,
+            ..Forwarding::default()
+        };
+        let headers = forwarded(&forwarding, &req);
+
+        assert!(!headers.contains_key(CONNECTION));
+        assert!(
+            !headers.contains_key(X_FORWARDED_FOR),
+            "Client information can be left out"
+        );
+    }
+
+    #[test]
+    fn preserve_uri_authority() {
+        let req = Request::builder()
+            .uri("https://example.com/x")
+            .body(())
+            .unwrap();
+        let forwarding = Forwarding {
+            preserve_host: true,
+            ..Forwarding::default()
+        };
+        let headers = forwarded(&forwarding, &req);
+
+        assert_eq!(
+            headers[HOST], "example.com",
+            "Host is preserved from the URI authority of HTTP/2 requests"
+        );
+    }
+
+    #[test]
+    fn client_cert_headers() {
+        let forwarding = Forwarding {
+            client_cert: ClientCertHeaders {
+                subject: Some(HeaderName::from_static("x-client-subject")),
+                san: Some(HeaderName::from_static("x-client-san")),
+                fingerprint: None,
+            },
+            ..Forwarding::default()
+        };
+
+        let mut req = mk_req([192, 0, 2, 1], &[("x-client-subject", "CN=admin")]);
+        assert!(
+            !forwarded(&forwarding, &req).contains_key("x-client-subject"),
+            "Identity headers from the client are removed"
+        );
+
+        req.extensions_mut().insert(TlsInfo {
+            client_cert: Some(crate::conn::ClientCert {
+                subject: String::from("CN=svc-a"),
+                sans: vec![String::from("a.internal"), String::from("b.internal")],
+                fingerprint: String::from("ab12"),
+            }),
+            ..TlsInfo::default()
+        });
+        let headers = forwarded(&forwarding, &req);
+        assert_eq!(headers["x-client-subject"], "CN=svc-a");
+        assert_eq!(
+            headers["x-client-san"], "a.internal, b.internal",
+            "The verified identity is passed upstream"
+        );
+    }
+}
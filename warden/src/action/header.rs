@@ -0,0 +1,211 @@
+use crate::conn::{ClientAddr, REQUEST_ID};
+use crate::trigger::host::authority;
+use crate::trigger::path::PathParams;
+use http::header::{Entry, HeaderName, HeaderValue};
+use http::{HeaderMap, Request};
+
+/// An update to request or response headers. Values are templates that may refer to captured
+/// path params, and to `{client_ip}`, `{request_id}`, `{host}`, `{method}` and `{path}`.
+pub enum HeaderUpdate {
+    Set(HeaderName, String),
+    Append(HeaderName, String),
+    Remove(HeaderName),
+    RemoveMatching(regex::Regex),
+    Rename(HeaderName, HeaderName),
+}
+
+impl HeaderUpdate {
+    /// Apply the update. Values whose templates expand to invalid header values are skipped.
+    pub fn apply(&self, headers: &mut HeaderMap, params: &PathParams) {
+        let expand = |template: &str| HeaderValue::from_str(&params.expand(template)).ok();
+
+        match self {
+            Self::Set(name, template) => {
+                if let Some(value) = expand(template) {
+                    headers.insert(name.clone(), value);
+                }
+            }
+            Self::Append(name, template) => {
+                if let Some(value) = expand(template) {
+                    headers.append(name.clone(), value);
+                }
+            }
+            Self::Remove(name) => {
+                headers.remove(name);
+            }
+            Self::RemoveMatching(pattern) => {
+                let names: Vec<HeaderName> = headers
+                    .keys()
+                    .filter(|name| pattern.is_match(name.as_str()))
+                    .cloned()
+                    .collect();
+
+                for name in names {
+                    headers.remove(name);
+                }
+            }
+            Self::Rename(from, to) => {
+                if let Entry::Occupied(entry) = headers.entry(from) {
+                    let (_, values) = entry.remove_entry_mult();
+                    let values: Vec<HeaderValue> = values.collect();
+
+                    headers.remove(to);
+                    for value in values {
+                        headers.append(to.clone(), value);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Names of the values every request provides to header templates, besides captured params.
+pub const BUILT_IN_PARAMS: [&str; 5] = ["client_ip", "request_id", "host", "method", "path"];
+
+/// Values available to header templates for a request. The built-in values take precedence over
+/// captured params of the same name, and expand to nothing when the request doesn't have them.
+pub fn template_params<T>(req: &Request<T>) -> PathParams {
+    let captured = req
+        .extensions()
+        .get::<PathParams>()
+        .cloned()
+        .unwrap_or_default();
+
+    let client_ip = req
+        .extensions()
+        .get::<ClientAddr>()
+        .map(|addr| addr.0.ip().to_string());
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .map(String::from);
+
+    let builtin = [
+        ("client_ip", client_ip),
+        ("request_id", request_id),
+        ("host", authority(req).map(|a| a.to_string())),
+        ("method", Some(req.method().to_string())),
+        ("path", Some(req.uri().path().to_string())),
+    ];
+
+    builtin
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.unwrap_or_default()))
+        .collect::<PathParams>()
+        .merge(captured)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn mk_headers(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|(k, v)| (HeaderName::from_static(k), HeaderValue::from_static(v)))
+            .collect()
+    }
+
+    #[test]
+    fn set_and_append() {
+        let mut headers = mk_headers(&[("x-a", "1")]);
+        let params = PathParams::from_iter([(String::from("id"), String::from("7"))]);
+
+        HeaderUpdate::Set(HeaderName::from_static("x-a"), String::from("user-{id}"))
+            .apply(&mut headers, &params);
+        assert_eq!(headers["x-a"], "user-7", "Set replaces values");
+
+        HeaderUpdate::Append(HeaderName::from_static("x-a"), String::from("2"))
+            .apply(&mut headers, &params);
+        assert_eq!(
+            headers.get_all("x-a").iter().count(),
+            2,
+            "Append keeps existing values"
+        );
+
+        HeaderUpdate::Set(HeaderName::from_static("x-b"), String::from("bad\n{id}"))
+            .apply(&mut headers, &params);
+        assert!(!headers.contains_key("x-b"), "Invalid values are skipped");
+    }
+
+    #[test]
+    fn remove() {
+        let mut headers = mk_headers(&[("x-a", "1"), ("x-debug-a", "1"), ("x-debug-b", "1")]);
+        let params = PathParams::default();
+
+        HeaderUpdate::Remove(HeaderName::from_static("x-a")).apply(&mut headers, &params);
+        assert!(!headers.contains_key("x-a"));
+
+        HeaderUpdate::RemoveMatching(regex::Regex::new("^x-debug-").unwrap())
+            .apply(&mut headers, &params);
+        assert!(headers.is_empty(), "Headers are removed by pattern");
+    }
+
+    #[test]
+    fn rename() {
+        let mut headers = mk_headers(&[("x-a", "1"), ("x-b", "old")]);
+        headers.append("x-a", HeaderValue::from_static("2"));
+
+        HeaderUpdate::Rename(
+            HeaderName::from_static("x-a"),
+            HeaderName::from_static("x-b"),
+        )
+        .apply(&mut headers, &PathParams::default());
+
+        assert!(!headers.contains_key("x-a"));
+        assert_eq!(
+            headers.get_all("x-b").iter().collect::<Vec<_>>(),
+            vec!["1", "2"],
+            "Rename moves every value, replacing the target"
+        );
+    }
+
+    #[test]
+    fn params() {
+        let mut req = Request::builder()
+            .method("POST")
+            .uri("/orders/7")
+            .header("host", "example.com")
+            .header(REQUEST_ID, "abc")
+            .body(())
+            .unwrap();
+        req.extensions_mut()
+            .insert(ClientAddr(SocketAddr::from(([10, 0, 0, 1], 4000))));
+        req.extensions_mut().insert(PathParams::from_iter([(
+            String::from("id"),
+            String::from("7"),
+        )]));
+
+        let params = template_params(&req);
+        assert_eq!(
+            params.expand("{client_ip} {request_id} {host} {method} {path} {id}"),
+            "10.0.0.1 abc example.com POST /orders/7 7",
+            "Templates can use request details and path params"
+        );
+
+        req.extensions_mut().insert(PathParams::from_iter([
+            (String::from("client_ip"), String::from("192.0.2.1")),
+            (String::from("request_id"), String::from("forged")),
+        ]));
+        req.extensions_mut().remove::<ClientAddr>();
+        req.headers_mut().remove(REQUEST_ID);
+
+        let params = template_params(&req);
+        assert_eq!(
+            params.expand("[{client_ip}] [{request_id}]"),
+            "[] []",
+            "Path params can't stand in for request details"
+        );
+
+        req.extensions_mut()
+            .insert(ClientAddr(SocketAddr::from(([10, 0, 0, 1], 4000))));
+        let params = template_params(&req);
+        assert_eq!(
+            params.expand("{client_ip}"),
+            "10.0.0.1",
+            "Request details take precedence over path params"
+        );
+    }
+}
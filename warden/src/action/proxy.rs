@@ -1,12 +1,28 @@
+use crate::action::forwarding::Forwarding;
+use crate::action::header::{self, HeaderUpdate};
+use crate::action::response::ResponseUpdate;
+use crate::action::upstream::{Balance, Balancer, Selected, Upstream};
+use crate::circuit::CircuitPolicy;
+use crate::client::UpstreamTls;
+use crate::health::HealthCheck;
+use crate::retry::RetryPolicy;
+use crate::timeout::Timeouts;
+use crate::trigger::path::PathParams;
 use http::request;
 use http::{Request, Uri};
 
 pub struct Proxy {
-    scheme: String,
-    host: String,
-    port: Option<u16>,
+    upstreams: Vec<Upstream>,
+    balancer: Balancer,
+    health_check: Option<HealthCheck>,
+    retry: Option<RetryPolicy>,
+    timeouts: Timeouts,
+    tls: Option<UpstreamTls>,
     path: Option<PathUpdate>,
     query: Option<QueryUpdate>,
+    forwarding: Forwarding,
+    headers: Vec<HeaderUpdate>,
+    response: Option<ResponseUpdate>,
 }
 
 impl Proxy {
@@ -14,59 +30,131 @@ impl Proxy {
         Builder::new()
     }
 
-    pub fn transform_req<T>(&self, req: &Request<T>) -> Option<request::Builder> {
-        let authority = match self.port {
-            Some(p) => format!("{}:{}", self.host, p),
-            None => self.host.clone(),
-        };
+    pub fn upstreams(&self) -> &[Upstream] {
+        &self.upstreams
+    }
 
-        let path = match &self.path {
-            Some(p) => p.apply(req.uri().path()),
-            None => req.uri().path().to_string(),
-        };
+    pub fn health_check(&self) -> Option<&HealthCheck> {
+        self.health_check.as_ref()
+    }
 
-        let query = match &self.query {
-            Some(q) => Some(q.apply(req.uri().query())),
-            None => req.uri().query().map(String::from),
-        };
+    pub fn retry_policy(&self) -> Option<&RetryPolicy> {
+        self.retry.as_ref()
+    }
 
-        let path_and_query = match query {
-            Some(q) => format!("{}?{}", path, q),
-            None => path,
-        };
+    pub fn timeouts(&self) -> &Timeouts {
+        &self.timeouts
+    }
+
+    pub fn upstream_tls(&self) -> Option<&UpstreamTls> {
+        self.tls.as_ref()
+    }
+
+    pub fn response_update(&self) -> Option<&ResponseUpdate> {
+        self.response.as_ref()
+    }
+
+    /// Choose a healthy upstream for a request.
+    pub fn select<T>(&self, req: &Request<T>) -> Option<Selected<'_>> {
+        self.balancer.select(&self.upstreams, req)
+    }
+
+    pub fn transform_req<T>(&self, req: &Request<T>) -> Option<request::Builder> {
+        let upstream = self.select(req)?;
+        self.transform_req_to(req, &upstream)
+    }
+
+    pub fn transform_req_to<T>(
+        &self,
+        req: &Request<T>,
+        upstream: &Upstream,
+    ) -> Option<request::Builder> {
+        let path_and_query = rewrite_path_and_query(req, self.path.as_ref(), self.query.as_ref());
 
         let uri = Uri::builder()
-            .scheme(self.scheme.as_str())
-            .authority(authority.as_str())
+            .scheme(upstream.scheme())
+            .authority(upstream.authority().as_str())
             .path_and_query(path_and_query)
             .build()
             .ok()?;
 
-        let mut builder = Request::builder()
-            .method(req.method())
-            .uri(uri)
-            .version(req.version());
+        // The HTTP version is negotiated separately on each hop, so the client's isn't copied.
+        let mut builder = Request::builder().method(req.method()).uri(uri);
 
         for (key, value) in req.headers().iter() {
             builder = builder.header(key, value);
         }
 
+        let headers = builder.headers_mut()?;
+        self.forwarding.apply(req, headers);
+
+        if !self.headers.is_empty() {
+            let params = header::template_params(req);
+
+            for update in &self.headers {
+                update.apply(headers, &params);
+            }
+        }
+
         Some(builder)
     }
 }
 
+/// Apply path and query updates to a request's path and query.
+pub(crate) fn rewrite_path_and_query<T>(
+    req: &Request<T>,
+    path: Option<&PathUpdate>,
+    query: Option<&QueryUpdate>,
+) -> String {
+    let no_params = PathParams::default();
+    let params = req.extensions().get::<PathParams>().unwrap_or(&no_params);
+
+    let path = match path {
+        Some(p) => p.apply(req.uri().path(), params),
+        None => req.uri().path().to_string(),
+    };
+
+    // Templated paths may carry query parameters, which take precedence over the request's own.
+    let (path, query_string) = match path.split_once('?') {
+        Some((p, q)) => (p.to_string(), Some(merge_query(q, req.uri().query()))),
+        None => (path, req.uri().query().map(String::from)),
+    };
+
+    let query_string = match query {
+        Some(q) => Some(q.apply(query_string.as_deref())),
+        None => query_string,
+    };
+
+    match query_string {
+        Some(q) => format!("{}?{}", path, q),
+        None => path,
+    }
+}
+
 pub enum PathUpdate {
     Replace(String),
     Prepend(String),
     Append(String),
+    Template(String),
+    StripPrefix(String),
+    RegexReplace {
+        pattern: regex::Regex,
+        replacement: String,
+    },
 }
 
 impl PathUpdate {
-    fn apply(&self, existing: &str) -> String {
+    fn apply(&self, existing: &str, params: &PathParams) -> String {
         match self {
             Self::Replace(p) => p.clone(),
-            Self::Prepend(p) => prepend_path(&p, existing),
-            Self::Append(p) => prepend_path(existing, &p),
+            Self::Prepend(p) => prepend_path(p, existing),
+            Self::Append(p) => prepend_path(existing, p),
+            Self::Template(t) => params.expand(t),
+            Self::StripPrefix(p) => strip_path_prefix(p, existing),
+            Self::RegexReplace {
+                pattern,
+                replacement,
+            } => rooted(pattern.replace(existing, replacement.as_str()).into_owned()),
         }
     }
 }
@@ -79,6 +167,43 @@ fn prepend_path(prefix: &str, rest: &str) -> String {
     )
 }
 
+/// Strip `prefix` from `path` on a segment boundary, so `/api` strips `/api/x` but not `/apix`.
+fn strip_path_prefix(prefix: &str, path: &str) -> String {
+    match path_suffix(prefix, path) {
+        Some(rest) => rooted(rest.to_string()),
+        None => path.to_string(),
+    }
+}
+
+/// The rest of `path` after `prefix`, if the prefix ends on a segment boundary: `/api` is a
+/// prefix of `/api` and `/api/x`, but not of `/apix`.
+pub(crate) fn path_suffix<'a>(prefix: &str, path: &'a str) -> Option<&'a str> {
+    let prefix = prefix.strip_suffix('/').unwrap_or(prefix);
+
+    path.strip_prefix(prefix)
+        .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+fn rooted(path: String) -> String {
+    if path.starts_with('/') {
+        path
+    } else {
+        format!("/{}", path)
+    }
+}
+
+fn merge_query(primary: &str, secondary: Option<&str>) -> String {
+    let mut merged = querystring::querify(primary);
+
+    for (k, v) in secondary.map(querystring::querify).unwrap_or_default() {
+        if !merged.iter().any(|(ke, _)| *ke == k) {
+            merged.push((k, v));
+        }
+    }
+
+    querystring::stringify(merged)
+}
+
 pub enum QueryUpdate {
     Replace(Vec<(String, String)>),
     Merge(Vec<(String, String)>),
@@ -96,7 +221,7 @@ impl QueryUpdate {
                 querystring::stringify(px)
             }
             Self::Merge(params) => {
-                let mut updated = existing.map(querystring::querify).unwrap_or(Vec::new());
+                let mut updated = existing.map(querystring::querify).unwrap_or_default();
 
                 for (k, v) in params {
                     let member = updated.iter().find(|(ke, _)| ke == k).is_some();
@@ -116,8 +241,24 @@ pub struct Builder {
     scheme: Option<String>,
     host: Option<String>,
     port: Option<u16>,
+    upstreams: Vec<Upstream>,
+    balance: Balance,
+    health_check: Option<HealthCheck>,
+    circuit_breaker: Option<CircuitPolicy>,
+    retry: Option<RetryPolicy>,
+    timeouts: Timeouts,
+    tls: Option<UpstreamTls>,
     path: Option<PathUpdate>,
     query: Option<QueryUpdate>,
+    forwarding: Forwarding,
+    headers: Vec<HeaderUpdate>,
+    response: Option<ResponseUpdate>,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Builder {
@@ -126,8 +267,18 @@ impl Builder {
             scheme: None,
             host: None,
             port: None,
+            upstreams: Vec::new(),
+            balance: Balance::RoundRobin,
+            health_check: None,
+            circuit_breaker: None,
+            retry: None,
+            timeouts: Timeouts::default(),
+            tls: None,
             path: None,
             query: None,
+            forwarding: Forwarding::default(),
+            headers: Vec::new(),
+            response: None,
         }
     }
 
@@ -152,6 +303,50 @@ impl Builder {
         }
     }
 
+    /// Add an upstream, alongside the one described by `scheme`, `host` and `port` if set.
+    pub fn upstream(mut self, upstream: Upstream) -> Self {
+        self.upstreams.push(upstream);
+        self
+    }
+
+    pub fn balance(self, balance: Balance) -> Self {
+        Self { balance, ..self }
+    }
+
+    pub fn health_check(self, health_check: HealthCheck) -> Self {
+        Self {
+            health_check: Some(health_check),
+            ..self
+        }
+    }
+
+    /// Apply a circuit breaker with this policy to every upstream.
+    pub fn circuit_breaker(self, circuit_breaker: CircuitPolicy) -> Self {
+        Self {
+            circuit_breaker: Some(circuit_breaker),
+            ..self
+        }
+    }
+
+    pub fn retry(self, retry: RetryPolicy) -> Self {
+        Self {
+            retry: Some(retry),
+            ..self
+        }
+    }
+
+    pub fn timeouts(self, timeouts: Timeouts) -> Self {
+        Self { timeouts, ..self }
+    }
+
+    /// TLS settings for connections to `https` upstreams.
+    pub fn tls(self, tls: UpstreamTls) -> Self {
+        Self {
+            tls: Some(tls),
+            ..self
+        }
+    }
+
     pub fn path(self, path: PathUpdate) -> Self {
         Self {
             path: Some(path),
@@ -166,16 +361,55 @@ impl Builder {
         }
     }
 
+    pub fn forwarding(self, forwarding: Forwarding) -> Self {
+        Self { forwarding, ..self }
+    }
+
+    /// Add an update to the headers of requests sent upstream, applied in the order added.
+    pub fn header(mut self, update: HeaderUpdate) -> Self {
+        self.headers.push(update);
+        self
+    }
+
+    pub fn response(self, response: ResponseUpdate) -> Self {
+        Self {
+            response: Some(response),
+            ..self
+        }
+    }
+
     pub fn build(self) -> Option<Proxy> {
-        let scheme = self.scheme?;
-        let host = self.host?;
+        let mut upstreams = match (self.scheme, self.host) {
+            (Some(scheme), Some(host)) => vec![Upstream::new(scheme, host, self.port)],
+            (None, None) => Vec::new(),
+            _ => return None,
+        };
+
+        upstreams.extend(self.upstreams);
+
+        if upstreams.is_empty() {
+            return None;
+        }
+
+        if let Some(policy) = self.circuit_breaker {
+            upstreams = upstreams
+                .into_iter()
+                .map(|u| u.circuit_breaker(policy.clone()))
+                .collect();
+        }
 
         Some(Proxy {
-            scheme,
-            host,
-            port: self.port,
+            balancer: Balancer::new(self.balance, &upstreams),
+            upstreams,
+            health_check: self.health_check,
+            retry: self.retry,
+            timeouts: self.timeouts,
+            tls: self.tls,
             path: self.path,
             query: self.query,
+            forwarding: self.forwarding,
+            headers: self.headers,
+            response: self.response,
         })
     }
 }
@@ -223,6 +457,28 @@ mod tests {
         );
     }
 
+    #[test]
+    fn http_version() {
+        let req = http::Request::builder()
+            .uri("https://bar.com")
+            .version(http::Version::HTTP_2)
+            .body(())
+            .unwrap();
+
+        let action = Proxy::builder()
+            .scheme(String::from("http"))
+            .host(String::from("foo.com"))
+            .build()
+            .unwrap();
+
+        let downstream = action.transform_req(&req).unwrap().body(()).unwrap();
+        assert_eq!(
+            downstream.version(),
+            http::Version::HTTP_11,
+            "The client's HTTP version is not forwarded"
+        );
+    }
+
     #[test]
     fn uri_authority() {
         let req = mk_req("https://bar.com");
@@ -470,4 +726,183 @@ mod tests {
             "QueryUpdate::Replace merges query in upstream request"
         );
     }
+
+    #[test]
+    fn uri_path_template() {
+        let mut req = mk_req("https://bar.com/users/7/orders/42?x=y&user=9");
+        let params = PathParams::from_iter(vec![
+            (String::from("id"), String::from("7")),
+            (String::from("order"), String::from("42")),
+        ]);
+        req.extensions_mut().insert(params);
+
+        let action = Proxy::builder()
+            .scheme(String::from("http"))
+            .host(String::from("foo.com"))
+            .path(PathUpdate::Template(String::from(
+                "/v2/orders/{order}?user={id}",
+            )))
+            .build()
+            .unwrap();
+
+        let downstream_uri = action
+            .transform_req(&req)
+            .unwrap()
+            .body(())
+            .unwrap()
+            .uri()
+            .clone();
+
+        assert_eq!(
+            downstream_uri.path(),
+            "/v2/orders/42",
+            "PathUpdate::Template substitutes captured path parameters"
+        );
+
+        assert_eq!(
+            downstream_uri.query().unwrap(),
+            "user=7&x=y&",
+            "PathUpdate::Template query parameters take precedence over upstream query"
+        );
+    }
+
+    #[test]
+    fn uri_path_rewrite() {
+        let scheme_http = http::uri::Scheme::from_str("http").unwrap();
+        let host = String::from("foo.com");
+
+        let rewrite = |update: PathUpdate, uri: &str| {
+            Proxy::builder()
+                .scheme(scheme_http.to_string())
+                .host(host.clone())
+                .path(update)
+                .build()
+                .unwrap()
+                .transform_req(&mk_req(uri))
+                .unwrap()
+                .body(())
+                .unwrap()
+                .uri()
+                .path()
+                .to_string()
+        };
+
+        let strip = || PathUpdate::StripPrefix(String::from("/api/svc/"));
+
+        assert_eq!(
+            rewrite(strip(), "https://bar.com/api/svc/rest"),
+            "/rest",
+            "PathUpdate::StripPrefix strips prefix from path in upstream request"
+        );
+
+        assert_eq!(
+            rewrite(strip(), "https://bar.com/api/svc"),
+            "/",
+            "PathUpdate::StripPrefix leaves a rooted path"
+        );
+
+        assert_eq!(
+            rewrite(strip(), "https://bar.com/api/svcx/rest"),
+            "/api/svcx/rest",
+            "PathUpdate::StripPrefix strips only on segment boundaries"
+        );
+
+        let regex_replace = PathUpdate::RegexReplace {
+            pattern: regex::Regex::new("^/old/(?P<section>[a-z]+)/(\\d+)$").unwrap(),
+            replacement: String::from("/new/$2/${section}"),
+        };
+
+        assert_eq!(
+            rewrite(regex_replace, "https://bar.com/old/docs/12"),
+            "/new/12/docs",
+            "PathUpdate::RegexReplace substitutes numbered and named groups"
+        );
+
+        let regex_replace = PathUpdate::RegexReplace {
+            pattern: regex::Regex::new("^/api").unwrap(),
+            replacement: String::new(),
+        };
+
+        assert_eq!(
+            rewrite(regex_replace, "https://bar.com/api"),
+            "/",
+            "PathUpdate::RegexReplace leaves a rooted path"
+        );
+    }
+
+    #[test]
+    fn upstreams() {
+        let req = mk_req("https://bar.com/x");
+
+        let action = Proxy::builder()
+            .scheme(String::from("http"))
+            .host(String::from("a.com"))
+            .upstream(Upstream::new(
+                String::from("https"),
+                String::from("b.com"),
+                Some(8443),
+            ))
+            .path(PathUpdate::Prepend(String::from("/y")))
+            .build()
+            .unwrap();
+
+        let downstream_uris: Vec<String> = (0..2)
+            .map(|_| {
+                action
+                    .transform_req(&req)
+                    .unwrap()
+                    .body(())
+                    .unwrap()
+                    .uri()
+                    .to_string()
+            })
+            .collect();
+
+        assert_eq!(
+            downstream_uris,
+            vec!["http://a.com/y/x", "https://b.com:8443/y/x"],
+            "Requests are spread over upstreams with the same rewriting"
+        );
+
+        let no_upstreams = Proxy::builder().build();
+        assert!(
+            no_upstreams.is_none(),
+            "Proxy requires at least one upstream"
+        );
+
+        let partial = Proxy::builder().scheme(String::from("http")).build();
+        assert!(partial.is_none(), "Proxy requires both scheme and host");
+    }
+
+    #[test]
+    fn header_updates() {
+        let req = Request::builder()
+            .uri("/x")
+            .header("x-internal-token", "secret")
+            .header("x-user", "alice")
+            .body(())
+            .unwrap();
+
+        let action = Proxy::builder()
+            .scheme(String::from("http"))
+            .host(String::from("a.com"))
+            .header(HeaderUpdate::Remove(http::header::HeaderName::from_static(
+                "x-internal-token",
+            )))
+            .header(HeaderUpdate::Set(
+                http::header::HeaderName::from_static("x-forwarded-path"),
+                String::from("{path}"),
+            ))
+            .build()
+            .unwrap();
+
+        let downstream = action.transform_req(&req).unwrap().body(()).unwrap();
+        let headers = downstream.headers();
+        assert!(!headers.contains_key("x-internal-token"));
+        assert_eq!(headers["x-user"], "alice", "Other headers are copied");
+        assert_eq!(
+            headers["x-forwarded-path"], "/x",
+            "Header updates apply to downstream requests"
+        );
+    }
 }
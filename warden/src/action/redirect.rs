@@ -0,0 +1,261 @@
+use crate::action::proxy::{rewrite_path_and_query, PathUpdate, QueryUpdate};
+use crate::conn::client_scheme;
+use crate::trigger::host::authority;
+use http::header::{HeaderValue, LOCATION};
+use http::{Request, Response, StatusCode};
+use hyper::Body;
+use std::fmt;
+
+/// Redirect clients to a location built from the request, e.g. the same path on another host.
+pub struct Redirect {
+    status: StatusCode,
+    scheme: Option<String>,
+    host: Option<String>,
+    path: Option<PathUpdate>,
+    query: Option<QueryUpdate>,
+}
+
+impl Redirect {
+    /// A redirect with status 301, 302, 307 or 308, or `None` for any other status.
+    pub fn new(status: StatusCode) -> Option<Self> {
+        match status {
+            StatusCode::MOVED_PERMANENTLY
+            | StatusCode::FOUND
+            | StatusCode::TEMPORARY_REDIRECT
+            | StatusCode::PERMANENT_REDIRECT => Some(Self {
+                status,
+                scheme: None,
+                host: None,
+                path: None,
+                query: None,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn scheme(self, scheme: String) -> Self {
+        Self {
+            scheme: Some(scheme),
+            ..self
+        }
+    }
+
+    /// Host, with an optional port, to redirect to.
+    pub fn host(self, host: String) -> Self {
+        Self {
+            host: Some(host),
+            ..self
+        }
+    }
+
+    pub fn path(self, path: PathUpdate) -> Self {
+        Self {
+            path: Some(path),
+            ..self
+        }
+    }
+
+    pub fn query(self, query: QueryUpdate) -> Self {
+        Self {
+            query: Some(query),
+            ..self
+        }
+    }
+
+    /// The location to redirect a request to. Without a scheme or host, this is relative to the
+    /// request's own host, with leading slashes collapsed so that it can't be read as a link to
+    /// another host. Changing only the scheme keeps the request's host, but not its port, and
+    /// fails if the request has no host: a relative location would redirect back here forever.
+    pub fn location<T>(&self, req: &Request<T>) -> Result<String, RedirectError> {
+        let path_and_query = rewrite_path_and_query(req, self.path.as_ref(), self.query.as_ref());
+
+        let host = match (&self.host, &self.scheme) {
+            (Some(host), _) => Some(host.clone()),
+            (None, Some(_)) => match authority(req) {
+                Some(a) => Some(a.host().to_string()),
+                None => return Err(RedirectError::NoHost),
+            },
+            (None, None) => None,
+        };
+
+        match host {
+            Some(host) => {
+                let scheme = self.scheme.as_deref().unwrap_or_else(|| client_scheme(req));
+
+                Ok(format!("{}://{}{}", scheme, host, path_and_query))
+            }
+            None => Ok(same_host(path_and_query)),
+        }
+    }
+
+    pub fn response<T>(&self, req: &Request<T>) -> Result<Response<Body>, RedirectError> {
+        let location = HeaderValue::from_str(&self.location(req)?)
+            .map_err(|_| RedirectError::InvalidLocation)?;
+
+        Response::builder()
+            .status(self.status)
+            .header(LOCATION, location)
+            .body(Body::empty())
+            .map_err(|_| RedirectError::InvalidLocation)
+    }
+}
+
+/// Why a redirect could not be built.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RedirectError {
+    /// The redirect changes only the scheme, and the request has no host to keep.
+    NoHost,
+    /// The location is not a valid header value.
+    InvalidLocation,
+}
+
+impl RedirectError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::NoHost => StatusCode::BAD_REQUEST,
+            Self::InvalidLocation => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for RedirectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoHost => write!(f, "request has no host to redirect to"),
+            Self::InvalidLocation => write!(f, "could not build redirect location"),
+        }
+    }
+}
+
+/// Browsers read a location starting `//` (or `/\`) as a link to another host, so collapse the
+/// leading slashes of a path into one.
+fn same_host(path_and_query: String) -> String {
+    if path_and_query.starts_with(['/', '\\']) {
+        format!("/{}", path_and_query.trim_start_matches(['/', '\\']))
+    } else {
+        path_and_query
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trigger::path::PathParams;
+    use http::header::HOST;
+
+    fn mk_req(uri: &str, host: &str) -> Request<()> {
+        Request::builder()
+            .uri(uri)
+            .header(HOST, host)
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn status() {
+        assert!(Redirect::new(StatusCode::PERMANENT_REDIRECT).is_some());
+        assert!(
+            Redirect::new(StatusCode::OK).is_none(),
+            "Redirects need a redirect status"
+        );
+    }
+
+    #[test]
+    fn force_https() {
+        let redirect = Redirect::new(StatusCode::MOVED_PERMANENTLY)
+            .unwrap()
+            .scheme(String::from("https"));
+
+        let req = mk_req("/a/b?c=d", "example.com:8080");
+        assert_eq!(
+            redirect.location(&req).unwrap(),
+            "https://example.com/a/b?c=d",
+            "Changing the scheme keeps the host, path and query"
+        );
+
+        let req = Request::builder().uri("/a").body(()).unwrap();
+        let err = redirect.response(&req).unwrap_err();
+        assert_eq!(
+            err,
+            RedirectError::NoHost,
+            "Changing the scheme needs a host to redirect to"
+        );
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn canonical_host() {
+        let redirect = Redirect::new(StatusCode::MOVED_PERMANENTLY)
+            .unwrap()
+            .host(String::from("www.example.com"));
+
+        let req = mk_req("/a", "example.com");
+        assert_eq!(redirect.location(&req).unwrap(), "http://www.example.com/a");
+
+        let res = redirect.response(&req).unwrap();
+        assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(res.headers()[LOCATION], "http://www.example.com/a");
+    }
+
+    #[test]
+    fn rewritten_path() {
+        let redirect = Redirect::new(StatusCode::FOUND)
+            .unwrap()
+            .path(PathUpdate::Template(String::from("/users/{id}?tab=posts")))
+            .query(QueryUpdate::Merge(vec![(
+                String::from("from"),
+                String::from("old"),
+            )]));
+
+        let mut req = mk_req("/u/7?sort=new", "example.com");
+        req.extensions_mut().insert(PathParams::from_iter([(
+            String::from("id"),
+            String::from("7"),
+        )]));
+
+        assert_eq!(
+            redirect.location(&req).unwrap(),
+            "/users/7?tab=posts&sort=new&from=old&",
+            "Locations reuse path and query updates"
+        );
+    }
+
+    #[test]
+    fn same_host_only() {
+        let plain = Redirect::new(StatusCode::FOUND).unwrap();
+        assert_eq!(
+            plain
+                .location(&mk_req("//evil.com/x", "example.com"))
+                .unwrap(),
+            "/evil.com/x",
+            "Relative locations can't point at another host"
+        );
+        assert_eq!(
+            plain
+                .location(&mk_req("/\\evil.com/x", "example.com"))
+                .unwrap(),
+            "/evil.com/x",
+            "Relative locations can't point at another host"
+        );
+
+        let stripped = Redirect::new(StatusCode::FOUND)
+            .unwrap()
+            .path(PathUpdate::StripPrefix(String::from("/old")));
+        assert_eq!(
+            stripped
+                .location(&mk_req("/old//evil.com/x", "example.com"))
+                .unwrap(),
+            "/evil.com/x",
+            "Rewritten relative locations can't point at another host"
+        );
+
+        let canonical = Redirect::new(StatusCode::FOUND)
+            .unwrap()
+            .host(String::from("www.example.com"));
+        assert_eq!(
+            canonical.location(&mk_req("//a/b", "example.com")).unwrap(),
+            "http://www.example.com//a/b",
+            "Paths are kept as they are when the location has a host"
+        );
+    }
+}
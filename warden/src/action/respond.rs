@@ -0,0 +1,85 @@
+use http::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use http::{HeaderMap, Response, StatusCode};
+use hyper::body::Bytes;
+use hyper::Body;
+
+/// A fixed response returned by warden itself, without contacting an upstream.
+pub struct Respond {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Respond {
+    pub fn new(status: StatusCode) -> Self {
+        Self {
+            status,
+            headers: HeaderMap::new(),
+            body: Bytes::new(),
+        }
+    }
+
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Set the body, along with a content type unless one has been given as a header.
+    pub fn body(mut self, body: Bytes, content_type: HeaderValue) -> Self {
+        self.headers.entry(CONTENT_TYPE).or_insert(content_type);
+        Self { body, ..self }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn response(&self) -> Response<Body> {
+        let mut res = Response::new(Body::from(self.body.clone()));
+        *res.status_mut() = self.status;
+        *res.headers_mut() = self.headers.clone();
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::CACHE_CONTROL;
+
+    #[tokio::test]
+    async fn response() {
+        let respond = Respond::new(StatusCode::SERVICE_UNAVAILABLE)
+            .header(CACHE_CONTROL, HeaderValue::from_static("no-store"))
+            .body(
+                Bytes::from("Down for maintenance"),
+                HeaderValue::from_static("text/plain"),
+            );
+
+        let res = respond.response();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[CACHE_CONTROL], "no-store");
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/plain");
+
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, "Down for maintenance");
+
+        let res = respond.response();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, "Down for maintenance", "Responses can be repeated");
+    }
+
+    #[test]
+    fn content_type() {
+        let respond = Respond::new(StatusCode::OK)
+            .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+            .body(Bytes::from("{}"), HeaderValue::from_static("text/plain"));
+
+        assert_eq!(
+            respond.response().headers()[CONTENT_TYPE],
+            "application/json",
+            "Content type headers take precedence over the body's own"
+        );
+    }
+}
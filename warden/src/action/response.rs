@@ -0,0 +1,375 @@
+use crate::action::header::{self, HeaderUpdate};
+use crate::action::proxy::path_suffix;
+use crate::action::upstream::Upstream;
+use crate::trigger::path::PathParams;
+use http::header::{HeaderValue, LOCATION, SET_COOKIE};
+use http::{Request, Response, StatusCode};
+
+/// A rewrite of the `Location` header of upstream responses.
+pub enum LocationRewrite {
+    /// Make absolute locations on the upstream relative, so that clients stay on warden.
+    Upstream,
+    /// Replace a prefix of the location, ending on a segment boundary. The replacement is a
+    /// header template.
+    Prefix { from: String, to: String },
+}
+
+impl LocationRewrite {
+    fn apply(&self, location: &str, upstream: &Upstream, params: &PathParams) -> Option<String> {
+        match self {
+            Self::Upstream => {
+                let origin = format!("{}://{}", upstream.scheme(), upstream.authority());
+                let rest = strip_prefix_ignore_case(location, &origin)?;
+
+                match rest {
+                    "" => Some(String::from("/")),
+                    rest if rest.starts_with('/') => Some(rest.to_string()),
+                    _ => None,
+                }
+            }
+            Self::Prefix { from, to } => {
+                // The query or fragment also ends the last segment of the prefix.
+                let end = location.find(['?', '#']).unwrap_or(location.len());
+                let (path, tail) = location.split_at(end);
+                let rest = path_suffix(from, path)?;
+                let to = params.expand(to);
+
+                match rest {
+                    "" => Some(format!("{}{}", to, tail)),
+                    rest => Some(format!("{}{}{}", to.trim_end_matches('/'), rest, tail)),
+                }
+            }
+        }
+    }
+}
+
+/// A rewrite of the `Domain` or `Path` attribute of upstream cookies.
+pub enum CookieRewrite {
+    /// Replace a domain, ignoring case and any leading dot. The replacement is a header template.
+    Domain { from: String, to: String },
+    /// Replace a path prefix, ending on a segment boundary.
+    Path { from: String, to: String },
+}
+
+impl CookieRewrite {
+    fn apply(&self, cookie: &str, params: &PathParams) -> Option<String> {
+        let mut rewritten = false;
+
+        let attributes: Vec<String> = cookie
+            .split(';')
+            .enumerate()
+            .map(|(i, attribute)| {
+                // The first pair is the cookie's own name and value.
+                if i == 0 {
+                    return attribute.trim().to_string();
+                }
+
+                let (name, value) = match attribute.split_once('=') {
+                    Some((name, value)) => (name.trim(), value.trim()),
+                    None => return attribute.trim().to_string(),
+                };
+
+                let replaced = match self {
+                    Self::Domain { from, to } if name.eq_ignore_ascii_case("domain") => {
+                        let domain = value.trim_start_matches('.');
+                        let from = from.trim_start_matches('.');
+
+                        domain.eq_ignore_ascii_case(from).then(|| params.expand(to))
+                    }
+                    Self::Path { from, to } if name.eq_ignore_ascii_case("path") => {
+                        path_suffix(from, value).map(|rest| match rest {
+                            "" => to.clone(),
+                            rest => format!("{}{}", to.trim_end_matches('/'), rest),
+                        })
+                    }
+                    _ => None,
+                };
+
+                match replaced {
+                    Some(value) => {
+                        rewritten = true;
+                        format!("{}={}", name, value)
+                    }
+                    None => attribute.trim().to_string(),
+                }
+            })
+            .collect();
+
+        rewritten.then(|| attributes.join("; "))
+    }
+}
+
+fn strip_prefix_ignore_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = s.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix)
+        .then(|| &s[prefix.len()..])
+}
+
+/// Updates to the responses of upstreams, applied before they are returned to clients.
+#[derive(Default)]
+pub struct ResponseUpdate {
+    status: Option<StatusCode>,
+    locations: Vec<LocationRewrite>,
+    cookies: Vec<CookieRewrite>,
+    headers: Vec<HeaderUpdate>,
+}
+
+impl ResponseUpdate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Override the status of every response.
+    pub fn status(self, status: StatusCode) -> Self {
+        Self {
+            status: Some(status),
+            ..self
+        }
+    }
+
+    /// Add a `Location` rewrite. The first that applies is used.
+    pub fn location(mut self, rewrite: LocationRewrite) -> Self {
+        self.locations.push(rewrite);
+        self
+    }
+
+    /// Add a `Set-Cookie` rewrite. Every rewrite is applied to every cookie.
+    pub fn cookie(mut self, rewrite: CookieRewrite) -> Self {
+        self.cookies.push(rewrite);
+        self
+    }
+
+    /// Add a header update, applied after the rewrites in the order added.
+    pub fn header(mut self, update: HeaderUpdate) -> Self {
+        self.headers.push(update);
+        self
+    }
+
+    pub fn apply<T, B>(&self, res: &mut Response<B>, req: &Request<T>, upstream: &Upstream) {
+        let params = header::template_params(req);
+
+        if let Some(status) = self.status {
+            *res.status_mut() = status;
+        }
+
+        let headers = res.headers_mut();
+
+        let location = headers.get(LOCATION).and_then(|l| l.to_str().ok());
+        let rewritten = location.and_then(|location| {
+            self.locations
+                .iter()
+                .find_map(|rewrite| rewrite.apply(location, upstream, &params))
+        });
+        if let Some(value) = rewritten.and_then(|l| HeaderValue::from_str(&l).ok()) {
+            headers.insert(LOCATION, value);
+        }
+
+        if !self.cookies.is_empty() {
+            let cookies: Vec<HeaderValue> = headers
+                .get_all(SET_COOKIE)
+                .iter()
+                .map(|cookie| {
+                    let rewritten = cookie.to_str().ok().and_then(|cookie| {
+                        self.cookies.iter().fold(None, |rewritten, rewrite| {
+                            let current = rewritten.as_deref().unwrap_or(cookie);
+                            rewrite.apply(current, &params).or(rewritten)
+                        })
+                    });
+
+                    rewritten
+                        .and_then(|c| HeaderValue::from_str(&c).ok())
+                        .unwrap_or_else(|| cookie.clone())
+                })
+                .collect();
+
+            headers.remove(SET_COOKIE);
+            for cookie in cookies {
+                headers.append(SET_COOKIE, cookie);
+            }
+        }
+
+        for update in &self.headers {
+            update.apply(headers, &params);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::{HeaderName, HOST};
+
+    fn upstream() -> Upstream {
+        Upstream::new(
+            String::from("http"),
+            String::from("app.internal"),
+            Some(8080),
+        )
+    }
+
+    fn mk_req() -> Request<()> {
+        Request::builder()
+            .uri("/api/login")
+            .header(HOST, "example.com")
+            .body(())
+            .unwrap()
+    }
+
+    fn mk_res(headers: &[(HeaderName, &'static str)]) -> Response<()> {
+        let mut res = Response::new(());
+        for (name, value) in headers {
+            res.headers_mut()
+                .append(name, HeaderValue::from_static(value));
+        }
+        res
+    }
+
+    #[test]
+    fn locations() {
+        let update = ResponseUpdate::new()
+            .location(LocationRewrite::Prefix {
+                from: String::from("http://app.internal:8080/v2/"),
+                to: String::from("https://{host}/api/"),
+            })
+            .location(LocationRewrite::Upstream);
+
+        let mut res = mk_res(&[(LOCATION, "http://app.internal:8080/v2/home")]);
+        update.apply(&mut res, &mk_req(), &upstream());
+        assert_eq!(
+            res.headers()[LOCATION],
+            "https://example.com/api/home",
+            "Prefixes are replaced with templates"
+        );
+
+        let mut res = mk_res(&[(LOCATION, "http://app.internal:8080/v2?x=1")]);
+        update.apply(&mut res, &mk_req(), &upstream());
+        assert_eq!(
+            res.headers()[LOCATION],
+            "https://example.com/api/?x=1",
+            "Prefixes match up to the query"
+        );
+
+        let mut res = mk_res(&[(LOCATION, "http://app.internal:8080/v2x/home")]);
+        update.apply(&mut res, &mk_req(), &upstream());
+        assert_eq!(
+            res.headers()[LOCATION],
+            "/v2x/home",
+            "Prefixes end on a segment boundary"
+        );
+
+        let mut res = mk_res(&[(LOCATION, "http://APP.internal:8080/home?x=1")]);
+        update.apply(&mut res, &mk_req(), &upstream());
+        assert_eq!(
+            res.headers()[LOCATION],
+            "/home?x=1",
+            "Locations on the upstream are made relative"
+        );
+
+        let mut res = mk_res(&[(LOCATION, "https://elsewhere.com/")]);
+        update.apply(&mut res, &mk_req(), &upstream());
+        assert_eq!(
+            res.headers()[LOCATION],
+            "https://elsewhere.com/",
+            "Other locations are untouched"
+        );
+
+        let api = ResponseUpdate::new().location(LocationRewrite::Prefix {
+            from: String::from("/api"),
+            to: String::from("/v1"),
+        });
+
+        let mut res = mk_res(&[(LOCATION, "/api/users")]);
+        api.apply(&mut res, &mk_req(), &upstream());
+        assert_eq!(res.headers()[LOCATION], "/v1/users");
+
+        let mut res = mk_res(&[(LOCATION, "/apiv2/users")]);
+        api.apply(&mut res, &mk_req(), &upstream());
+        assert_eq!(
+            res.headers()[LOCATION],
+            "/apiv2/users",
+            "Prefixes don't match part of a segment"
+        );
+    }
+
+    #[test]
+    fn cookies() {
+        let update = ResponseUpdate::new()
+            .cookie(CookieRewrite::Domain {
+                from: String::from("app.internal"),
+                to: String::from("{host}"),
+            })
+            .cookie(CookieRewrite::Path {
+                from: String::from("/"),
+                to: String::from("/api/"),
+            });
+
+        let mut res = mk_res(&[
+            (
+                SET_COOKIE,
+                "session=abc; Domain=.app.internal; Path=/; HttpOnly",
+            ),
+            (SET_COOKIE, "theme=dark"),
+        ]);
+        update.apply(&mut res, &mk_req(), &upstream());
+
+        let cookies: Vec<_> = res.headers().get_all(SET_COOKIE).iter().collect();
+        assert_eq!(
+            cookies,
+            vec![
+                "session=abc; Domain=example.com; Path=/api/; HttpOnly",
+                "theme=dark"
+            ],
+            "Cookie domains and paths are rewritten"
+        );
+    }
+
+    #[test]
+    fn cookie_attributes_only() {
+        let update = ResponseUpdate::new()
+            .cookie(CookieRewrite::Domain {
+                from: String::from("app.internal"),
+                to: String::from("{host}"),
+            })
+            .cookie(CookieRewrite::Path {
+                from: String::from("/api"),
+                to: String::from("/v2"),
+            });
+
+        let mut res = mk_res(&[
+            (SET_COOKIE, "domain=app.internal; Path=/apix"),
+            (SET_COOKIE, "path=/api; Path=/api/x"),
+            (SET_COOKIE, "id=1; Path=/api"),
+        ]);
+        update.apply(&mut res, &mk_req(), &upstream());
+
+        let cookies: Vec<_> = res.headers().get_all(SET_COOKIE).iter().collect();
+        assert_eq!(
+            cookies,
+            vec![
+                "domain=app.internal; Path=/apix",
+                "path=/api; Path=/v2/x",
+                "id=1; Path=/v2"
+            ],
+            "Only cookie attributes are rewritten, and paths only on segment boundaries"
+        );
+    }
+
+    #[test]
+    fn status_and_headers() {
+        let update = ResponseUpdate::new()
+            .status(StatusCode::OK)
+            .header(HeaderUpdate::Remove(HeaderName::from_static("server")))
+            .header(HeaderUpdate::Set(
+                HeaderName::from_static("x-served-by"),
+                String::from("warden"),
+            ));
+
+        let mut res = mk_res(&[(HeaderName::from_static("server"), "app/1.0")]);
+        *res.status_mut() = StatusCode::NOT_FOUND;
+        update.apply(&mut res, &mk_req(), &upstream());
+
+        assert_eq!(res.status(), StatusCode::OK, "Status is overridden");
+        assert!(!res.headers().contains_key("server"));
+        assert_eq!(res.headers()["x-served-by"], "warden");
+    }
+}
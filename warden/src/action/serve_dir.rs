@@ -0,0 +1,305 @@
+use crate::action::proxy::{rewrite_path_and_query, PathUpdate};
+use futures_util::stream;
+use http::header::{
+    HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_NONE_MATCH,
+    LAST_MODIFIED,
+};
+use http::{Method, Request, Response, StatusCode, Uri};
+use hyper::body::HttpBody;
+use hyper::Body;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use tower::ServiceExt;
+use tower_http::services::fs::ServeFileSystemResponseBody;
+use tower_http::services::{self, ServeFile};
+
+/// Serve files from a local directory. Paths that would escape the directory are not found.
+pub struct ServeDir {
+    inner: services::ServeDir,
+    path: Option<PathUpdate>,
+    fallback: Option<PathBuf>,
+}
+
+impl ServeDir {
+    /// Serve files from `dir`, with `index.html` for directories.
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            inner: services::ServeDir::new(dir),
+            path: None,
+            fallback: None,
+        }
+    }
+
+    pub fn index(self, index: bool) -> Self {
+        Self {
+            inner: self.inner.append_index_html_on_directories(index),
+            ..self
+        }
+    }
+
+    /// Serve `<file>.gz` to clients that accept gzip, when it exists.
+    pub fn precompressed_gzip(self) -> Self {
+        Self {
+            inner: self.inner.precompressed_gzip(),
+            ..self
+        }
+    }
+
+    /// Serve `<file>.br` to clients that accept brotli, when it exists.
+    pub fn precompressed_br(self) -> Self {
+        Self {
+            inner: self.inner.precompressed_br(),
+            ..self
+        }
+    }
+
+    /// Update the request path before looking it up, e.g. to strip the prefix a rule matched.
+    pub fn path(self, path: PathUpdate) -> Self {
+        Self {
+            path: Some(path),
+            ..self
+        }
+    }
+
+    /// File to serve when nothing else is found, such as the `index.html` of a single-page app.
+    pub fn fallback(self, fallback: PathBuf) -> Self {
+        Self {
+            fallback: Some(fallback),
+            ..self
+        }
+    }
+
+    pub async fn serve<T>(&self, req: &Request<T>) -> io::Result<Response<Body>> {
+        let path_and_query = rewrite_path_and_query(req, self.path.as_ref(), None);
+        let uri = Uri::builder()
+            .path_and_query(path_and_query)
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let file_req = || {
+            let mut file_req = Request::new(Body::empty());
+            *file_req.method_mut() = req.method().clone();
+            *file_req.uri_mut() = uri.clone();
+            *file_req.headers_mut() = req.headers().clone();
+            file_req
+        };
+
+        let mut res = self.inner.clone().oneshot(file_req()).await?;
+
+        if res.status() == StatusCode::NOT_FOUND {
+            if let Some(fallback) = &self.fallback {
+                res = ServeFile::new(fallback).oneshot(file_req()).await?;
+            }
+        }
+
+        let (mut parts, body) = res.into_parts();
+
+        if let Some(etag) = etag(&parts) {
+            if is_get_or_head(req.method()) && none_match(req, &etag) {
+                let mut not_modified = Response::new(Body::empty());
+                *not_modified.status_mut() = StatusCode::NOT_MODIFIED;
+                not_modified.headers_mut().insert(ETAG, etag);
+                if let Some(last_modified) = parts.headers.remove(LAST_MODIFIED) {
+                    not_modified
+                        .headers_mut()
+                        .insert(LAST_MODIFIED, last_modified);
+                }
+
+                return Ok(not_modified);
+            }
+
+            parts.headers.insert(ETAG, etag);
+        }
+
+        Ok(Response::from_parts(parts, into_body(body)))
+    }
+}
+
+fn is_get_or_head(method: &Method) -> bool {
+    method == Method::GET || method == Method::HEAD
+}
+
+/// A weak entity tag derived from the file's modification time, size and encoding.
+fn etag(parts: &http::response::Parts) -> Option<HeaderValue> {
+    let last_modified = parts.headers.get(LAST_MODIFIED)?;
+
+    let size = match parts.status {
+        StatusCode::OK => parts.headers.get(CONTENT_LENGTH)?.to_str().ok()?,
+        // Partial responses give the full size after the range, as in `bytes 0-9/1234`.
+        StatusCode::PARTIAL_CONTENT => {
+            parts
+                .headers
+                .get(CONTENT_RANGE)?
+                .to_str()
+                .ok()?
+                .rsplit_once('/')?
+                .1
+        }
+        _ => return None,
+    };
+
+    let mut hasher = DefaultHasher::new();
+    last_modified.hash(&mut hasher);
+    parts.headers.get(CONTENT_ENCODING).hash(&mut hasher);
+
+    HeaderValue::from_str(&format!("W/\"{}-{:x}\"", size, hasher.finish())).ok()
+}
+
+/// Whether `If-None-Match` matches `etag`, using weak comparison.
+fn none_match<T>(req: &Request<T>, etag: &HeaderValue) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = match etag.to_str() {
+        Ok(etag) => opaque(etag),
+        Err(_) => return false,
+    };
+
+    req.headers()
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+fn into_body(mut body: ServeFileSystemResponseBody) -> Body {
+    Body::wrap_stream(stream::poll_fn(move |cx| Pin::new(&mut body).poll_data(cx)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::{ACCEPT_ENCODING, CONTENT_TYPE, RANGE};
+    use std::fs;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("warden-{}-{}", name, std::process::id()));
+            fs::create_dir_all(dir.join("assets")).unwrap();
+            fs::write(dir.join("index.html"), "<h1>app</h1>").unwrap();
+            fs::write(dir.join("assets/app.js"), "console.log(1)").unwrap();
+            fs::write(dir.join("assets/app.js.gz"), "gzipped").unwrap();
+
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn mk_req(uri: &str) -> Request<()> {
+        Request::builder().uri(uri).body(()).unwrap()
+    }
+
+    async fn body(res: Response<Body>) -> String {
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_files() {
+        let dir = TempDir::new("serve");
+        let serve = ServeDir::new(&dir.0);
+
+        let res = serve.serve(&mk_req("/assets/app.js")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()[CONTENT_TYPE],
+            "application/javascript",
+            "Content types are detected"
+        );
+        assert!(res.headers().contains_key(LAST_MODIFIED));
+        assert_eq!(body(res).await, "console.log(1)");
+
+        let res = serve.serve(&mk_req("/")).await.unwrap();
+        assert_eq!(body(res).await, "<h1>app</h1>", "Index files are served");
+
+        let res = serve.serve(&mk_req("/../etc/passwd")).await.unwrap();
+        assert_eq!(
+            res.status(),
+            StatusCode::NOT_FOUND,
+            "Paths cannot escape the directory"
+        );
+    }
+
+    #[tokio::test]
+    async fn ranges_and_precompression() {
+        let dir = TempDir::new("range");
+        let serve = ServeDir::new(&dir.0).precompressed_gzip();
+
+        let req = Request::builder()
+            .uri("/assets/app.js")
+            .header(RANGE, "bytes=0-6")
+            .body(())
+            .unwrap();
+        let res = serve.serve(&req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body(res).await, "console", "Ranges are served");
+
+        let req = Request::builder()
+            .uri("/assets/app.js")
+            .header(ACCEPT_ENCODING, "gzip")
+            .body(())
+            .unwrap();
+        let res = serve.serve(&req).await.unwrap();
+        assert_eq!(res.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(body(res).await, "gzipped", "Precompressed files are served");
+    }
+
+    #[tokio::test]
+    async fn etags() {
+        let dir = TempDir::new("etag");
+        let serve = ServeDir::new(&dir.0);
+
+        let res = serve.serve(&mk_req("/index.html")).await.unwrap();
+        let etag = res.headers()[ETAG].clone();
+        assert!(etag.to_str().unwrap().starts_with("W/"), "ETags are weak");
+
+        let req = Request::builder()
+            .uri("/index.html")
+            .header(IF_NONE_MATCH, etag.clone())
+            .body(())
+            .unwrap();
+        let res = serve.serve(&req).await.unwrap();
+        assert_eq!(
+            res.status(),
+            StatusCode::NOT_MODIFIED,
+            "Matching ETags are not modified"
+        );
+        assert_eq!(res.headers()[ETAG], etag);
+
+        let req = Request::builder()
+            .uri("/index.html")
+            .header(IF_NONE_MATCH, "\"other\"")
+            .body(())
+            .unwrap();
+        let res = serve.serve(&req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn path_and_fallback() {
+        let dir = TempDir::new("fallback");
+        let serve = ServeDir::new(&dir.0)
+            .path(PathUpdate::StripPrefix(String::from("/static")))
+            .fallback(dir.0.join("index.html"));
+
+        let res = serve.serve(&mk_req("/static/assets/app.js")).await.unwrap();
+        assert_eq!(body(res).await, "console.log(1)", "Paths are updated");
+
+        let res = serve.serve(&mk_req("/static/users/7")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            body(res).await,
+            "<h1>app</h1>",
+            "Missing files are served from the fallback"
+        );
+    }
+}
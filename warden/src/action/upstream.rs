@@ -0,0 +1,593 @@
+use crate::circuit::{CircuitBreaker, CircuitPolicy, CircuitState};
+use crate::conn::ClientAddr;
+use crate::health::HealthState;
+use http::header::{HeaderName, COOKIE};
+use http::Request;
+use rand::Rng;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Points placed on the consistent hash ring per unit of upstream weight.
+const RING_POINTS_PER_WEIGHT: usize = 40;
+
+/// Largest upstream weight; larger weights are capped.
+pub const MAX_WEIGHT: u32 = 1000;
+
+pub struct Upstream {
+    scheme: String,
+    host: String,
+    port: Option<u16>,
+    weight: u32,
+    outstanding: Arc<AtomicUsize>,
+    health: Arc<HealthState>,
+    circuit: Option<CircuitBreaker>,
+}
+
+impl Upstream {
+    pub fn new(scheme: String, host: String, port: Option<u16>) -> Self {
+        Self {
+            scheme,
+            host,
+            port,
+            weight: 1,
+            outstanding: Arc::new(AtomicUsize::new(0)),
+            health: Arc::new(HealthState::default()),
+            circuit: None,
+        }
+    }
+
+    pub fn circuit_breaker(self, policy: CircuitPolicy) -> Self {
+        Self {
+            circuit: Some(CircuitBreaker::new(policy)),
+            ..self
+        }
+    }
+
+    /// Relative share of traffic, between 1 and [`MAX_WEIGHT`].
+    pub fn weight(self, weight: u32) -> Self {
+        Self {
+            weight: weight.clamp(1, MAX_WEIGHT),
+            ..self
+        }
+    }
+
+    pub fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    pub fn authority(&self) -> String {
+        match self.port {
+            Some(p) => format!("{}:{}", self.host, p),
+            None => self.host.clone(),
+        }
+    }
+
+    /// Requests currently in flight to this upstream.
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::Relaxed)
+    }
+
+    pub fn health(&self) -> &Arc<HealthState> {
+        &self.health
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.health.is_healthy()
+    }
+
+    pub fn circuit_state(&self) -> Option<CircuitState> {
+        self.circuit.as_ref().map(CircuitBreaker::state)
+    }
+
+    pub fn circuit(&self) -> Option<&CircuitBreaker> {
+        self.circuit.as_ref()
+    }
+
+    /// Record the outcome of a request to this upstream. Connect errors, timeouts and 5xx
+    /// responses are failures.
+    pub fn record(&self, success: bool) {
+        if let Some(state) = self.circuit.as_ref().and_then(|c| c.record(success)) {
+            self.log_circuit(state);
+        }
+    }
+
+    fn log_circuit(&self, state: CircuitState) {
+        match state {
+            CircuitState::Open => {
+                tracing::warn!("circuit for upstream {} opened", self.authority())
+            }
+            CircuitState::HalfOpen => {
+                tracing::info!("circuit for upstream {} half-open", self.authority())
+            }
+            CircuitState::Closed => {
+                tracing::info!("circuit for upstream {} closed", self.authority())
+            }
+        }
+    }
+
+    /// Start a request, unless the circuit breaker refuses it.
+    fn try_begin(&self) -> Option<InFlight> {
+        if let Some(circuit) = &self.circuit {
+            if let Some(state) = circuit.try_acquire().ok()? {
+                self.log_circuit(state);
+            }
+        }
+
+        self.outstanding.fetch_add(1, Ordering::Relaxed);
+
+        Some(InFlight {
+            outstanding: self.outstanding.clone(),
+        })
+    }
+}
+
+/// Marks a request as outstanding against an upstream until dropped.
+pub struct InFlight {
+    outstanding: Arc<AtomicUsize>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// An upstream chosen for a request, counted as outstanding for as long as this is held.
+pub struct Selected<'a> {
+    upstream: &'a Upstream,
+    in_flight: InFlight,
+}
+
+impl<'a> Selected<'a> {
+    pub fn into_in_flight(self) -> InFlight {
+        self.in_flight
+    }
+}
+
+impl<'a> Deref for Selected<'a> {
+    type Target = Upstream;
+
+    fn deref(&self) -> &Upstream {
+        self.upstream
+    }
+}
+
+pub enum Balance {
+    RoundRobin,
+    WeightedRoundRobin,
+    LeastOutstanding,
+    RandomTwoChoices,
+    ConsistentHash(HashKey),
+}
+
+pub enum HashKey {
+    Header(HeaderName),
+    Cookie(String),
+    ClientIp,
+}
+
+impl HashKey {
+    fn hash<T>(&self, req: &Request<T>) -> Option<u64> {
+        match self {
+            Self::Header(name) => req.headers().get(name).map(|v| hash(v.as_bytes())),
+            Self::Cookie(name) => cookie(req, name).map(|v| hash(v.as_bytes())),
+            Self::ClientIp => req
+                .extensions()
+                .get::<ClientAddr>()
+                .map(|ClientAddr(addr)| hash(&addr.ip())),
+        }
+    }
+}
+
+fn cookie<'a, T>(req: &'a Request<T>, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+fn hash<H: Hash + ?Sized>(value: &H) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Spreads requests over a set of upstreams according to a `Balance` strategy.
+pub struct Balancer {
+    balance: Balance,
+    next: AtomicUsize,
+    ring: Vec<(u64, usize)>,
+}
+
+impl Balancer {
+    pub fn new(balance: Balance, upstreams: &[Upstream]) -> Self {
+        let ring = match balance {
+            Balance::ConsistentHash(_) => hash_ring(upstreams),
+            _ => Vec::new(),
+        };
+
+        Self {
+            balance,
+            next: AtomicUsize::new(0),
+            ring,
+        }
+    }
+
+    pub fn select<'a, T>(
+        &self,
+        upstreams: &'a [Upstream],
+        req: &Request<T>,
+    ) -> Option<Selected<'a>> {
+        let mut candidates: Vec<usize> = (0..upstreams.len())
+            .filter(|i| upstreams[*i].is_healthy())
+            .collect();
+
+        // The circuit breaker is consulted only for the upstream actually picked, so that
+        // half-open trials are reserved atomically; refused upstreams drop out and we pick again.
+        loop {
+            let index = self.pick(upstreams, &candidates, req)?;
+            let upstream = &upstreams[index];
+
+            match upstream.try_begin() {
+                Some(in_flight) => {
+                    return Some(Selected {
+                        upstream,
+                        in_flight,
+                    })
+                }
+                None => candidates.retain(|i| *i != index),
+            }
+        }
+    }
+
+    fn pick<T>(
+        &self,
+        upstreams: &[Upstream],
+        candidates: &[usize],
+        req: &Request<T>,
+    ) -> Option<usize> {
+        if candidates.is_empty() {
+            return None;
+        }
+
+        match &self.balance {
+            Balance::RoundRobin => Some(candidates[self.tick() % candidates.len()]),
+            Balance::WeightedRoundRobin => {
+                let total: usize = candidates
+                    .iter()
+                    .map(|i| upstreams[*i].weight as usize)
+                    .sum();
+                let mut n = self.tick() % total;
+
+                candidates.iter().copied().find(|i| {
+                    let weight = upstreams[*i].weight as usize;
+                    if n < weight {
+                        true
+                    } else {
+                        n -= weight;
+                        false
+                    }
+                })
+            }
+            Balance::LeastOutstanding => {
+                // Rotate the starting point so that ties are spread evenly.
+                let start = self.tick() % candidates.len();
+
+                candidates
+                    .iter()
+                    .cycle()
+                    .skip(start)
+                    .take(candidates.len())
+                    .copied()
+                    .min_by_key(|i| upstreams[*i].outstanding())
+            }
+            Balance::RandomTwoChoices => {
+                if candidates.len() == 1 {
+                    return Some(candidates[0]);
+                }
+
+                // Two distinct candidates, chosen uniformly.
+                let mut rng = rand::thread_rng();
+                let ia = rng.gen_range(0..candidates.len());
+                let ib = (ia + rng.gen_range(1..candidates.len())) % candidates.len();
+                let (a, b) = (candidates[ia], candidates[ib]);
+
+                if upstreams[b].outstanding() < upstreams[a].outstanding() {
+                    Some(b)
+                } else {
+                    Some(a)
+                }
+            }
+            Balance::ConsistentHash(key) => match key.hash(req) {
+                Some(h) => {
+                    let start = self.ring.partition_point(|(point, _)| *point < h);
+
+                    self.ring
+                        .iter()
+                        .cycle()
+                        .skip(start)
+                        .take(self.ring.len())
+                        .map(|(_, i)| *i)
+                        .find(|i| candidates.contains(i))
+                }
+                // Requests without the key are spread round-robin.
+                None => Some(candidates[self.tick() % candidates.len()]),
+            },
+        }
+    }
+
+    fn tick(&self) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+fn hash_ring(upstreams: &[Upstream]) -> Vec<(u64, usize)> {
+    let mut ring: Vec<(u64, usize)> = upstreams
+        .iter()
+        .enumerate()
+        .flat_map(|(i, u)| {
+            let points = u.weight.min(MAX_WEIGHT) as usize * RING_POINTS_PER_WEIGHT;
+            (0..points).map(move |point| (hash(&format!("{}#{}", u.authority(), point)), i))
+        })
+        .collect();
+
+    ring.sort_unstable();
+    ring
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::HealthCheck;
+    use std::net::SocketAddr;
+
+    fn mk_upstreams(n: usize) -> Vec<Upstream> {
+        (0..n)
+            .map(|i| Upstream::new(String::from("http"), format!("u{}.internal", i), None))
+            .collect()
+    }
+
+    fn mk_req() -> Request<()> {
+        Request::builder().uri("/").body(()).unwrap()
+    }
+
+    fn pick_host(balancer: &Balancer, upstreams: &[Upstream], req: &Request<()>) -> String {
+        balancer.select(upstreams, req).unwrap().authority()
+    }
+
+    #[test]
+    fn round_robin() {
+        let upstreams = mk_upstreams(3);
+        let balancer = Balancer::new(Balance::RoundRobin, &upstreams);
+
+        let picks: Vec<String> = (0..6)
+            .map(|_| pick_host(&balancer, &upstreams, &mk_req()))
+            .collect();
+
+        assert_eq!(
+            picks,
+            vec![
+                "u0.internal",
+                "u1.internal",
+                "u2.internal",
+                "u0.internal",
+                "u1.internal",
+                "u2.internal"
+            ],
+            "Balance::RoundRobin cycles through upstreams in order"
+        );
+    }
+
+    #[test]
+    fn weighted_round_robin() {
+        let mut upstreams = mk_upstreams(2);
+        let heavy = upstreams.remove(1).weight(3);
+        upstreams.push(heavy);
+
+        let balancer = Balancer::new(Balance::WeightedRoundRobin, &upstreams);
+
+        let heavy_picks = (0..8)
+            .filter(|_| pick_host(&balancer, &upstreams, &mk_req()) == "u1.internal")
+            .count();
+
+        assert_eq!(
+            heavy_picks, 6,
+            "Balance::WeightedRoundRobin picks upstreams in proportion to weight"
+        );
+    }
+
+    #[test]
+    fn least_outstanding() {
+        let upstreams = mk_upstreams(3);
+        let balancer = Balancer::new(Balance::LeastOutstanding, &upstreams);
+
+        let first = balancer.select(&upstreams, &mk_req()).unwrap();
+        let second = balancer.select(&upstreams, &mk_req()).unwrap();
+        let third = balancer.select(&upstreams, &mk_req()).unwrap();
+
+        let mut hosts = vec![first.authority(), second.authority(), third.authority()];
+        hosts.sort();
+        assert_eq!(
+            hosts,
+            vec!["u0.internal", "u1.internal", "u2.internal"],
+            "Balance::LeastOutstanding avoids upstreams with requests in flight"
+        );
+
+        let host = second.authority();
+        drop(second);
+
+        assert_eq!(
+            pick_host(&balancer, &upstreams, &mk_req()),
+            host,
+            "Balance::LeastOutstanding picks the upstream with fewest requests in flight"
+        );
+
+        drop(first);
+        drop(third);
+        assert!(
+            upstreams.iter().all(|u| u.outstanding() == 0),
+            "Selections stop counting as outstanding once dropped"
+        );
+    }
+
+    #[test]
+    fn random_two_choices() {
+        let upstreams = mk_upstreams(2);
+        let balancer = Balancer::new(Balance::RandomTwoChoices, &upstreams);
+
+        let _busy = (0..5)
+            .map(|_| upstreams[0].try_begin().unwrap())
+            .collect::<Vec<_>>();
+
+        for _ in 0..20 {
+            let selected = balancer.select(&upstreams, &mk_req()).unwrap();
+            assert_ne!(
+                selected.authority(),
+                "u0.internal",
+                "Balance::RandomTwoChoices prefers the less loaded of two choices"
+            );
+        }
+    }
+
+    #[test]
+    fn weight_bounds() {
+        let heavy = mk_upstreams(1).remove(0).weight(u32::MAX);
+        assert_eq!(heavy.weight, MAX_WEIGHT, "Weights are capped");
+
+        let ring = hash_ring(&[heavy]);
+        assert_eq!(ring.len(), MAX_WEIGHT as usize * RING_POINTS_PER_WEIGHT);
+    }
+
+    #[test]
+    fn consistent_hash() {
+        let upstreams = mk_upstreams(5);
+        let key = HashKey::Header(HeaderName::from_static("x-user"));
+        let balancer = Balancer::new(Balance::ConsistentHash(key), &upstreams);
+
+        let mk_user_req = |user: &str| {
+            Request::builder()
+                .uri("/")
+                .header("X-User", user)
+                .body(())
+                .unwrap()
+        };
+
+        for user in ["alice", "bob", "carol"] {
+            let first = pick_host(&balancer, &upstreams, &mk_user_req(user));
+
+            for _ in 0..5 {
+                assert_eq!(
+                    pick_host(&balancer, &upstreams, &mk_user_req(user)),
+                    first,
+                    "Balance::ConsistentHash picks the same upstream for the same key"
+                );
+            }
+        }
+
+        let spread: std::collections::HashSet<String> = (0..50)
+            .map(|i| pick_host(&balancer, &upstreams, &mk_user_req(&format!("user{}", i))))
+            .collect();
+        assert!(
+            spread.len() > 1,
+            "Balance::ConsistentHash spreads different keys over upstreams"
+        );
+    }
+
+    #[test]
+    fn skips_unhealthy() {
+        let upstreams = mk_upstreams(3);
+        let check = HealthCheck {
+            fall: 1,
+            ..HealthCheck::default()
+        };
+        upstreams[1].health().record(&check, false);
+
+        for balance in [
+            Balance::RoundRobin,
+            Balance::WeightedRoundRobin,
+            Balance::LeastOutstanding,
+            Balance::RandomTwoChoices,
+            Balance::ConsistentHash(HashKey::ClientIp),
+        ] {
+            let balancer = Balancer::new(balance, &upstreams);
+
+            for _ in 0..10 {
+                assert_ne!(
+                    pick_host(&balancer, &upstreams, &mk_req()),
+                    "u1.internal",
+                    "Unhealthy upstreams are skipped"
+                );
+            }
+        }
+
+        upstreams[0].health().record(&check, false);
+        upstreams[2].health().record(&check, false);
+
+        let balancer = Balancer::new(Balance::RoundRobin, &upstreams);
+        assert!(
+            balancer.select(&upstreams, &mk_req()).is_none(),
+            "Nothing is selected when every upstream is unhealthy"
+        );
+    }
+
+    #[test]
+    fn skips_open_circuits() {
+        let policy = CircuitPolicy {
+            failure_threshold: 2,
+            ..CircuitPolicy::default()
+        };
+        let upstreams: Vec<Upstream> = mk_upstreams(2)
+            .into_iter()
+            .map(|u| u.circuit_breaker(policy.clone()))
+            .collect();
+        let balancer = Balancer::new(Balance::RoundRobin, &upstreams);
+
+        upstreams[0].record(false);
+        upstreams[0].record(false);
+        assert_eq!(upstreams[0].circuit_state(), Some(CircuitState::Open));
+
+        for _ in 0..4 {
+            assert_eq!(
+                pick_host(&balancer, &upstreams, &mk_req()),
+                "u1.internal",
+                "Upstreams with open circuits are skipped"
+            );
+        }
+    }
+
+    #[test]
+    fn hash_keys() {
+        let addr: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let mut req = Request::builder()
+            .uri("/")
+            .header("Cookie", "a=1; session=abc")
+            .body(())
+            .unwrap();
+        req.extensions_mut().insert(ClientAddr(addr));
+
+        assert_eq!(
+            HashKey::Cookie(String::from("session")).hash(&req),
+            Some(hash("abc".as_bytes())),
+            "HashKey::Cookie hashes the named cookie"
+        );
+
+        assert_eq!(
+            HashKey::Cookie(String::from("missing")).hash(&req),
+            None,
+            "HashKey::Cookie is absent without the named cookie"
+        );
+
+        assert_eq!(
+            HashKey::ClientIp.hash(&req),
+            Some(hash(&addr.ip())),
+            "HashKey::ClientIp hashes the client address"
+        );
+    }
+}
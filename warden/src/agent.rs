@@ -1,43 +1,388 @@
 use crate::args::Args;
 use crate::action::Action;
 use crate::action::proxy::Proxy;
+use crate::circuit;
+use crate::client::Clients;
+use crate::config::{self, Config};
+use crate::health;
+use crate::listener::ListenerSettings;
+use crate::trigger::path::PathParams;
 use crate::trigger::Trigger;
+use crate::watch;
 use http::Request;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 pub type Ruleset = Arc<Vec<Rule>>;
 
+/// Shared, swappable reference to the live ruleset. Readers take a snapshot per request, so
+/// in-flight requests keep the rules they started with when the ruleset is replaced.
+#[derive(Clone)]
+pub struct RulesetHandle(Arc<RwLock<Ruleset>>);
+
+impl RulesetHandle {
+    pub fn new(ruleset: Ruleset) -> Self {
+        Self(Arc::new(RwLock::new(ruleset)))
+    }
+
+    pub fn current(&self) -> Ruleset {
+        self.0.read().expect("ruleset lock not poisoned").clone()
+    }
+
+    pub fn replace(&self, ruleset: Ruleset) {
+        *self.0.write().expect("ruleset lock not poisoned") = ruleset;
+    }
+}
+
 pub struct Rule {
     trigger: Trigger,
     action: Action
 }
 
 impl Rule {
+    pub fn new(trigger: Trigger, action: Action) -> Self {
+        Self { trigger, action }
+    }
+
     pub fn applies<T>(&self, req: &Request<T>) -> bool {
         self.trigger.applies(req)
     }
 
+    /// The path parameters captured by the rule's trigger, if it applies.
+    pub fn matches<T>(&self, req: &Request<T>) -> Option<PathParams> {
+        self.trigger.captures(req)
+    }
+
+    pub fn action(&self) -> &Action {
+        &self.action
+    }
+
     pub fn transform_req<T>(&self, req: Request<T>) -> Option<Request<T>> {
         self.action.transform_req(req)
     }
 }
 
-pub fn start(args: &Args) -> Ruleset {
+/// A listener defined in the config file, and its live ruleset.
+#[derive(Clone)]
+pub struct ListenerRules {
+    pub settings: ListenerSettings,
+    pub rules: RulesetHandle,
+}
+
+/// The live rulesets: the shared one, and one for each listener defined in the config file.
+/// Listeners without rules of their own get the shared rules.
+#[derive(Clone)]
+pub struct Rulesets {
+    pub shared: RulesetHandle,
+    pub listeners: Vec<ListenerRules>,
+}
+
+impl Rulesets {
+    /// Snapshots of the shared ruleset and every listener's ruleset.
+    pub fn current(&self) -> Vec<Ruleset> {
+        let listeners = self.listeners.iter().map(|l| l.rules.current());
+        std::iter::once(self.shared.current())
+            .chain(listeners)
+            .collect()
+    }
+}
+
+/// Rulesets read from the config file, each followed by the default rule.
+pub struct Loaded {
+    pub shared: Ruleset,
+    pub listeners: Vec<(ListenerSettings, Ruleset)>,
+}
+
+pub fn start(args: &Args, clients: &Clients) -> Result<Rulesets, config::Error> {
+    let loaded = load(args)?;
+    watch_health(&loaded, &[], clients);
+
+    let rulesets = Rulesets {
+        shared: RulesetHandle::new(loaded.shared),
+        listeners: loaded
+            .listeners
+            .into_iter()
+            .map(|(settings, ruleset)| ListenerRules {
+                settings,
+                rules: RulesetHandle::new(ruleset),
+            })
+            .collect(),
+    };
+
+    if let Some(path) = args.config.clone() {
+        let reloader = Reloader {
+            args: args.clone(),
+            rulesets: rulesets.clone(),
+            clients: clients.clone(),
+        };
+        let poll = (args.config_poll_secs > 0).then(|| Duration::from_secs(args.config_poll_secs));
+
+        watch::spawn(vec![path], poll, "config file changed", move |reason| {
+            reload_logged(&reloader, reason)
+        });
+    }
+
+    Ok(rulesets)
+}
+
+pub fn load(args: &Args) -> Result<Loaded, config::Error> {
+    let config = match &args.config {
+        Some(path) => config::load(path)?,
+        None => Config {
+            rules: Vec::new(),
+            listeners: Vec::new(),
+        },
+    };
+
+    let shared = with_default_rule(args, config.rules);
+    let listeners = config
+        .listeners
+        .into_iter()
+        .map(|listener| {
+            let ruleset = match listener.rules {
+                Some(rules) => with_default_rule(args, rules),
+                None => shared.clone(),
+            };
+            (listener.settings, ruleset)
+        })
+        .collect();
+
+    Ok(Loaded { shared, listeners })
+}
+
+fn with_default_rule(args: &Args, mut rules: Vec<Rule>) -> Ruleset {
+    if let Some(default_rule) = default_rule(args) {
+        rules.push(default_rule);
+    }
+
+    Arc::new(rules)
+}
+
+/// Reload every ruleset, leaving the current rules live if any of the new ones are invalid.
+/// Listeners are bound at startup, so a config file whose listeners were added, removed or
+/// changed is rejected until restart.
+pub fn reload(args: &Args, rulesets: &Rulesets, clients: &Clients) -> Result<(), config::Error> {
+    let loaded = load(args)?;
+
+    let running = rulesets.listeners.iter().map(|l| &l.settings);
+    if !running.eq(loaded.listeners.iter().map(|(settings, _)| settings)) {
+        return Err(config::Error::ListenersChanged);
+    }
+
+    let current = rulesets.current();
+    watch_health(&loaded, &proxies(&current), clients);
+
+    for (listener, (_, ruleset)) in rulesets.listeners.iter().zip(&loaded.listeners) {
+        listener.rules.replace(ruleset.clone());
+    }
+    rulesets.shared.replace(loaded.shared);
+
+    Ok(())
+}
+
+/// Start health checks for the loaded rulesets, carrying over the health of upstreams unchanged
+/// from the `previous` proxies.
+fn watch_health(loaded: &Loaded, previous: &[&Proxy], clients: &Clients) {
+    watch_ruleset_health(&loaded.shared, previous, clients);
+
+    for (_, ruleset) in &loaded.listeners {
+        // Listeners using the shared rules are already watched.
+        if !Arc::ptr_eq(ruleset, &loaded.shared) {
+            watch_ruleset_health(ruleset, previous, clients);
+        }
+    }
+}
+
+fn watch_ruleset_health(ruleset: &Ruleset, previous: &[&Proxy], clients: &Clients) {
+    for rule in ruleset.iter() {
+        match rule.action() {
+            Action::Proxy(proxy) => {
+                health::inherit(proxy, previous);
+                circuit::inherit(proxy, previous);
+                let connect = proxy.timeouts().or(clients.default_timeouts()).connect;
+                match clients.client(connect, proxy.upstream_tls()) {
+                    Ok(client) => health::watch(proxy, &client),
+                    Err(err) => tracing::error!("cannot build health check client: {}", err),
+                }
+            }
+            Action::Redirect(_) | Action::Respond(_) | Action::ServeDir(_) => {}
+        }
+    }
+}
+
+fn proxies(rulesets: &[Ruleset]) -> Vec<&Proxy> {
+    rulesets
+        .iter()
+        .flat_map(|ruleset| ruleset.iter())
+        .filter_map(|rule| match rule.action() {
+            Action::Proxy(proxy) => Some(proxy.as_ref()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[derive(Clone)]
+struct Reloader {
+    args: Args,
+    rulesets: Rulesets,
+    clients: Clients,
+}
+
+fn reload_logged(reloader: &Reloader, reason: &str) {
+    match reload(&reloader.args, &reloader.rulesets, &reloader.clients) {
+        Ok(()) => tracing::info!("ruleset reloaded ({})", reason),
+        Err(err) => tracing::error!("ruleset reload rejected ({}): {}", reason, err),
+    }
+}
+
+fn default_rule(args: &Args) -> Option<Rule> {
     let default_proxy = Proxy::builder()
-        .scheme(args.downstream_scheme.clone())
-        .host(args.downstream_host.clone());
+        .scheme(args.downstream_scheme.clone()?)
+        .host(args.downstream_host.clone()?);
 
     let default_proxy = match args.downstream_port {
         Some(port) => default_proxy.port(port),
         None => default_proxy,
     };
 
-    let default_proxy = default_proxy.build().expect("default downstream proxy is valid");
+    let default_proxy = default_proxy
+        .build()
+        .expect("default downstream proxy is valid");
 
-    let default_rule = Rule {
+    Some(Rule {
         trigger: Trigger::catch_all(),
-        action: Action::Proxy(default_proxy),
-    };
+        action: Action::Proxy(Box::new(default_proxy)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientConfig;
+    use clap::Parser;
+    use http::StatusCode;
+
+    fn status(ruleset: &Ruleset) -> StatusCode {
+        match ruleset[0].action() {
+            Action::Respond(respond) => respond.status(),
+            _ => panic!("expected a respond action"),
+        }
+    }
+
+    #[tokio::test]
+    async fn reload_ruleset() {
+        let dir = std::env::temp_dir().join(format!("warden-agent-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rules.toml");
+        std::fs::write(&path, "[[rules]]\naction.respond.status = 200\n").unwrap();
+
+        let args = Args::parse_from([
+            "warden",
+            "--config",
+            path.to_str().unwrap(),
+            "--config-poll-secs",
+            "0",
+        ]);
+        let clients = Clients::new(ClientConfig::default());
+        let rulesets = start(&args, &clients).unwrap();
+
+        let snapshot = rulesets.shared.current();
+        assert_eq!(status(&snapshot), StatusCode::OK);
+
+        std::fs::write(&path, "[[rules]]\naction.respond.status = 503\n").unwrap();
+        reload(&args, &rulesets, &clients).unwrap();
+        assert_eq!(
+            status(&rulesets.shared.current()),
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Reloading replaces the live ruleset"
+        );
+        assert_eq!(
+            status(&snapshot),
+            StatusCode::OK,
+            "Requests keep the ruleset they started with"
+        );
 
-    Arc::new(vec![default_rule])
+        std::fs::write(&path, "[[rules]]\naction.respond.status = 42\n").unwrap();
+        assert!(
+            reload(&args, &rulesets, &clients).is_err(),
+            "Invalid configs are rejected"
+        );
+        assert_eq!(
+            status(&rulesets.shared.current()),
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The current ruleset stays live when a reload is rejected"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reload_listener_rulesets() {
+        let dir =
+            std::env::temp_dir().join(format!("warden-agent-listeners-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rules.toml");
+        let config = |shared: u16, own: u16, port: u16| {
+            format!(
+                r#"
+                rules = [{{ action.respond.status = {} }}]
+
+                [[listeners]]
+                port = {}
+                rules = [{{ action.respond.status = {} }}]
+
+                [[listeners]]
+                port = 9001
+            "#,
+                shared, port, own
+            )
+        };
+        std::fs::write(&path, config(200, 201, 9000)).unwrap();
+
+        let args = Args::parse_from([
+            "warden",
+            "--config",
+            path.to_str().unwrap(),
+            "--config-poll-secs",
+            "0",
+        ]);
+        let clients = Clients::new(ClientConfig::default());
+        let rulesets = start(&args, &clients).unwrap();
+        let statuses = |rulesets: &Rulesets| {
+            rulesets
+                .current()
+                .iter()
+                .map(|ruleset| status(ruleset).as_u16())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            statuses(&rulesets),
+            vec![200, 201, 200],
+            "Listeners without rules use the shared rules"
+        );
+
+        std::fs::write(&path, config(202, 203, 9000)).unwrap();
+        reload(&args, &rulesets, &clients).unwrap();
+        assert_eq!(
+            statuses(&rulesets),
+            vec![202, 203, 202],
+            "Reloading replaces the shared ruleset and each listener's ruleset"
+        );
+
+        std::fs::write(&path, config(204, 205, 9002)).unwrap();
+        assert!(
+            matches!(
+                reload(&args, &rulesets, &clients),
+                Err(config::Error::ListenersChanged)
+            ),
+            "Configs with different listeners are rejected"
+        );
+        assert_eq!(
+            statuses(&rulesets),
+            vec![202, 203, 202],
+            "The current rulesets stay live when listeners change"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
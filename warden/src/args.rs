@@ -1,28 +1,109 @@
+use crate::tls::{ClientAuthMode, TlsVersion};
 use clap::Parser;
+use std::path::PathBuf;
 
-#[derive(Parser)]
+#[derive(Clone, Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
     /// Address to listen on
     #[arg(long = "addr", default_value = "::1")]
     pub addr: String,
 
-    /// Port to listen on
-    #[arg(long = "port")]
-    pub port: u16,
+    /// Port to listen on with the shared rules (more listeners can be defined in the config file)
+    #[arg(long = "port", required_unless_present = "config")]
+    pub port: Option<u16>,
+
+    /// Config file (TOML, YAML or JSON) with rules, evaluated before the default downstream
+    /// server, and listeners
+    #[arg(long = "config")]
+    pub config: Option<PathBuf>,
+
+    /// Interval in seconds between checks for changes to the ruleset, TLS certificate and client
+    /// CA files (0 disables polling; SIGHUP always reloads)
+    #[arg(long = "config-poll-secs", default_value = "5")]
+    pub config_poll_secs: u64,
 
     /// Scheme for default downstream server
-    #[arg(long = "ds-scheme")]
-    pub downstream_scheme: String,
+    #[arg(long = "ds-scheme", required_unless_present = "config", requires = "downstream_host")]
+    pub downstream_scheme: Option<String>,
 
     /// Host for default downstream server
-    #[arg(long = "ds-host")]
-    pub downstream_host: String,
+    #[arg(long = "ds-host", required_unless_present = "config", requires = "downstream_scheme")]
+    pub downstream_host: Option<String>,
 
     /// Port for default downstream server
     #[arg(long = "ds-port")]
     pub downstream_port: Option<u16>,
 
+    /// Seconds an idle downstream connection is kept in the pool (0 keeps it indefinitely)
+    #[arg(long = "pool-idle-timeout-secs", default_value = "90")]
+    pub pool_idle_timeout_secs: u64,
+
+    /// Maximum idle downstream connections kept per host
+    #[arg(long = "pool-max-idle-per-host")]
+    pub pool_max_idle_per_host: Option<usize>,
+
+    /// Only use HTTP/2 (with prior knowledge) for downstream connections
+    #[arg(long = "http2-only")]
+    pub http2_only: bool,
+
+    /// Interval in seconds between HTTP/2 keep-alive pings to downstream servers
+    #[arg(long = "http2-keep-alive-secs")]
+    pub http2_keep_alive_secs: Option<u64>,
+
+    /// Use adaptive flow control for downstream HTTP/2 connections
+    #[arg(long = "http2-adaptive-window")]
+    pub http2_adaptive_window: bool,
+
+    /// Milliseconds allowed to connect to a downstream server (0 disables)
+    #[arg(long = "connect-timeout-ms", default_value = "10000")]
+    pub connect_timeout_ms: u64,
+
+    /// Milliseconds allowed between sending a request downstream and receiving the response
+    /// headers (0 disables)
+    #[arg(long = "first-byte-timeout-ms", default_value = "60000")]
+    pub first_byte_timeout_ms: u64,
+
+    /// Milliseconds allowed for a whole downstream exchange, including retries and the response
+    /// body (0 disables)
+    #[arg(long = "total-timeout-ms", default_value = "0")]
+    pub total_timeout_ms: u64,
+
+    /// Milliseconds a downstream response body may stall between chunks (0 disables)
+    #[arg(long = "idle-body-timeout-ms", default_value = "60000")]
+    pub idle_body_timeout_ms: u64,
+
+    /// Template for error response bodies (JSON if it ends in .json, HTML if .html, otherwise
+    /// plain text); {status}, {reason}, {message} and {request_id} are substituted
+    #[arg(long = "error-template")]
+    pub error_template: Option<PathBuf>,
+
+    /// PEM certificate chain to serve over TLS; repeat for several hostnames (chosen by SNI, the
+    /// first is the default)
+    #[arg(long = "tls-cert", requires_all = ["tls_key", "port"])]
+    pub tls_cert: Vec<PathBuf>,
+
+    /// PEM private key for each --tls-cert, in the same order
+    #[arg(long = "tls-key", requires = "tls_cert")]
+    pub tls_key: Vec<PathBuf>,
+
+    /// Minimum TLS version accepted (1.2 or 1.3)
+    #[arg(long = "tls-min-version", default_value = "1.2")]
+    pub tls_min_version: TlsVersion,
+
+    /// Protocols offered with ALPN, in order of preference
+    #[arg(long = "tls-alpn", value_delimiter = ',', default_value = "h2,http/1.1")]
+    pub tls_alpn: Vec<String>,
+
+    /// PEM bundle of certificate authorities for verifying client certificates; enables client
+    /// certificate authentication
+    #[arg(long = "tls-client-ca", requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// Whether clients must present a certificate (require) or may omit one (request)
+    #[arg(long = "tls-client-auth", default_value = "require", requires = "tls_client_ca")]
+    pub tls_client_auth: ClientAuthMode,
+
     /// Log level
     #[arg(long = "log", default_value = "debug")]
     pub log_level: String,
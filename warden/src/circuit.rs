@@ -0,0 +1,368 @@
+use crate::action::proxy::Proxy;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// When to stop sending traffic to an upstream based on the outcome of real requests.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CircuitPolicy {
+    /// Consecutive failures (connect errors, timeouts or 5xx responses) that open the circuit.
+    pub failure_threshold: u32,
+    /// How long an open circuit rejects traffic before allowing trial requests.
+    pub cool_down: Duration,
+    /// Trial requests allowed while half-open; all must succeed to close the circuit.
+    pub half_open_trials: u32,
+}
+
+impl Default for CircuitPolicy {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cool_down: Duration::from_secs(30),
+            half_open_trials: 1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// A circuit breaker refused to let a request through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CircuitOpen;
+
+#[derive(Clone, Copy)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        since: Instant,
+    },
+    HalfOpen {
+        since: Instant,
+        started: u32,
+        successes: u32,
+    },
+}
+
+pub struct CircuitBreaker {
+    policy: CircuitPolicy,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(policy: CircuitPolicy) -> Self {
+        Self {
+            policy,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        match *self.lock() {
+            State::Closed { .. } => CircuitState::Closed,
+            State::Open { .. } => CircuitState::Open,
+            State::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Reserve a request, if the circuit lets one through now: always when closed, and up to the
+    /// trial count once an open circuit's cool-down has passed, moving it to half-open. Returns
+    /// the new state if it changed.
+    pub fn try_acquire(&self) -> Result<Option<CircuitState>, CircuitOpen> {
+        let mut state = self.lock();
+
+        match *state {
+            State::Closed { .. } => Ok(None),
+            State::Open { since } if since.elapsed() >= self.policy.cool_down => {
+                *state = State::HalfOpen {
+                    since: Instant::now(),
+                    started: 1,
+                    successes: 0,
+                };
+                Ok(Some(CircuitState::HalfOpen))
+            }
+            State::Open { .. } => Err(CircuitOpen),
+            // Trials whose outcome never arrives (e.g. cancelled requests) must not hold the
+            // circuit half-open forever, so they are abandoned after another cool-down.
+            State::HalfOpen { since, .. } if self.trials_stalled(since) => {
+                *state = State::HalfOpen {
+                    since: Instant::now(),
+                    started: 1,
+                    successes: 0,
+                };
+                Ok(None)
+            }
+            State::HalfOpen {
+                ref mut started, ..
+            } if *started < self.policy.half_open_trials => {
+                *started += 1;
+                Ok(None)
+            }
+            State::HalfOpen { .. } => Err(CircuitOpen),
+        }
+    }
+
+    /// Take on the state of `previous`, if it has the same policy.
+    pub fn inherit(&self, previous: &CircuitBreaker) {
+        if self.policy == previous.policy {
+            let state = *previous.lock();
+            *self.lock() = state;
+        }
+    }
+
+    /// Record the outcome of a request. Returns the new state if it changed.
+    pub fn record(&self, success: bool) -> Option<CircuitState> {
+        let mut state = self.lock();
+
+        match *state {
+            State::Closed { ref mut failures } => {
+                if success {
+                    *failures = 0;
+                    None
+                } else {
+                    *failures += 1;
+
+                    if *failures >= self.policy.failure_threshold {
+                        *state = State::Open {
+                            since: Instant::now(),
+                        };
+                        Some(CircuitState::Open)
+                    } else {
+                        None
+                    }
+                }
+            }
+            State::Open { .. } => None,
+            State::HalfOpen {
+                ref mut successes, ..
+            } => {
+                if !success {
+                    *state = State::Open {
+                        since: Instant::now(),
+                    };
+                    return Some(CircuitState::Open);
+                }
+
+                *successes += 1;
+
+                if *successes >= self.policy.half_open_trials {
+                    *state = State::Closed { failures: 0 };
+                    Some(CircuitState::Closed)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn trials_stalled(&self, since: Instant) -> bool {
+        since.elapsed() >= self.policy.cool_down
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("circuit lock not poisoned")
+    }
+}
+
+/// Carry circuit state over from `previous` proxies (i.e. before a reload) for upstreams with the
+/// same scheme and authority, so a reload does not close circuits that are open.
+pub fn inherit(proxy: &Proxy, previous: &[&Proxy]) {
+    for upstream in proxy.upstreams() {
+        let circuit = match upstream.circuit() {
+            Some(c) => c,
+            None => continue,
+        };
+
+        let known = previous
+            .iter()
+            .flat_map(|p| p.upstreams())
+            .filter(|u| u.scheme() == upstream.scheme() && u.authority() == upstream.authority())
+            .find_map(|u| u.circuit());
+
+        if let Some(known) = known {
+            circuit.inherit(known);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::upstream::Upstream;
+
+    fn mk_breaker(cool_down: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(CircuitPolicy {
+            failure_threshold: 3,
+            cool_down,
+            half_open_trials: 2,
+        })
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = mk_breaker(Duration::from_secs(60));
+
+        assert_eq!(breaker.record(false), None);
+        assert_eq!(breaker.record(false), None);
+        assert_eq!(breaker.record(true), None);
+        assert_eq!(
+            breaker.state(),
+            CircuitState::Closed,
+            "A success resets the failure count"
+        );
+
+        assert_eq!(breaker.record(false), None);
+        assert_eq!(breaker.record(false), None);
+        assert_eq!(
+            breaker.record(false),
+            Some(CircuitState::Open),
+            "The circuit opens after `failure_threshold` consecutive failures"
+        );
+        assert_eq!(
+            breaker.try_acquire(),
+            Err(CircuitOpen),
+            "An open circuit rejects traffic"
+        );
+    }
+
+    #[test]
+    fn half_open_trials() {
+        let breaker = mk_breaker(Duration::ZERO);
+        (0..3).for_each(|_| {
+            breaker.record(false);
+        });
+
+        assert_eq!(
+            breaker.try_acquire(),
+            Ok(Some(CircuitState::HalfOpen)),
+            "An open circuit permits a trial after the cool-down"
+        );
+        assert_eq!(breaker.try_acquire(), Ok(None));
+
+        assert_eq!(breaker.record(true), None);
+        assert_eq!(
+            breaker.record(true),
+            Some(CircuitState::Closed),
+            "Successful trials close the circuit"
+        );
+    }
+
+    #[test]
+    fn half_open_failure() {
+        let breaker = mk_breaker(Duration::ZERO);
+        (0..3).for_each(|_| {
+            breaker.record(false);
+        });
+
+        breaker.try_acquire().unwrap();
+        assert_eq!(
+            breaker.record(false),
+            Some(CircuitState::Open),
+            "A failed trial re-opens the circuit"
+        );
+    }
+
+    #[test]
+    fn half_open_limits_trials() {
+        let breaker = mk_breaker(Duration::from_millis(50));
+        (0..3).for_each(|_| {
+            breaker.record(false);
+        });
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(
+            breaker.try_acquire().is_ok() && breaker.try_acquire().is_ok(),
+            "Half-open circuits permit up to the trial count"
+        );
+        assert_eq!(
+            breaker.try_acquire(),
+            Err(CircuitOpen),
+            "Half-open circuits permit only the trial count"
+        );
+    }
+
+    #[test]
+    fn concurrent_trials() {
+        let breaker = std::sync::Arc::new(mk_breaker(Duration::from_millis(50)));
+        (0..3).for_each(|_| {
+            breaker.record(false);
+        });
+        std::thread::sleep(Duration::from_millis(60));
+
+        let barrier = std::sync::Arc::new(std::sync::Barrier::new(16));
+        let threads: Vec<_> = (0..16)
+            .map(|_| {
+                let breaker = breaker.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    breaker.try_acquire().is_ok()
+                })
+            })
+            .collect();
+        let permitted = threads
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .filter(|ok| *ok)
+            .count();
+
+        assert_eq!(
+            permitted, 2,
+            "Concurrent requests to a recovering upstream get only the trial count"
+        );
+    }
+
+    #[test]
+    fn inherit_state() {
+        let policy = CircuitPolicy {
+            failure_threshold: 1,
+            ..CircuitPolicy::default()
+        };
+        let proxy = |policy: &CircuitPolicy| {
+            Proxy::builder()
+                .scheme(String::from("http"))
+                .host(String::from("a.internal"))
+                .upstream(Upstream::new(
+                    String::from("http"),
+                    String::from("b.internal"),
+                    None,
+                ))
+                .circuit_breaker(policy.clone())
+                .build()
+                .unwrap()
+        };
+
+        let previous = proxy(&policy);
+        previous.upstreams()[0].record(false);
+
+        let reloaded = proxy(&policy);
+        inherit(&reloaded, &[&previous]);
+        assert_eq!(
+            reloaded.upstreams()[0].circuit_state(),
+            Some(CircuitState::Open),
+            "Unchanged upstreams keep their circuit state across reloads"
+        );
+        assert_eq!(
+            reloaded.upstreams()[1].circuit_state(),
+            Some(CircuitState::Closed),
+            "Upstreams only inherit their own circuit state"
+        );
+
+        let changed = CircuitPolicy {
+            failure_threshold: 2,
+            ..policy.clone()
+        };
+        let rebuilt = proxy(&changed);
+        inherit(&rebuilt, &[&previous]);
+        assert_eq!(
+            rebuilt.upstreams()[0].circuit_state(),
+            Some(CircuitState::Closed),
+            "Circuits start closed when their policy changes"
+        );
+    }
+}
@@ -0,0 +1,268 @@
+use crate::args::Args;
+use crate::timeout::Timeouts;
+use http::uri::Scheme;
+use http::Uri;
+use hyper::client::HttpConnector;
+use hyper::Client;
+use hyper_tls::MaybeHttpsStream;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tower::Service;
+
+pub type HttpClient = Client<UpstreamConnector>;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Connection pool and protocol settings for the client used to reach downstream servers.
+#[derive(Clone)]
+pub struct ClientConfig {
+    pub pool_idle_timeout: Option<Duration>,
+    pub pool_max_idle_per_host: usize,
+    pub http2_only: bool,
+    pub http2_keep_alive_interval: Option<Duration>,
+    pub http2_adaptive_window: bool,
+    /// Timeouts for proxy actions that don't set their own.
+    pub timeouts: Timeouts,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            pool_idle_timeout: Some(Duration::from_secs(90)),
+            pool_max_idle_per_host: usize::MAX,
+            http2_only: false,
+            http2_keep_alive_interval: None,
+            http2_adaptive_window: false,
+            timeouts: Timeouts::default(),
+        }
+    }
+}
+
+impl From<&Args> for ClientConfig {
+    fn from(args: &Args) -> Self {
+        Self {
+            pool_idle_timeout: match args.pool_idle_timeout_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            pool_max_idle_per_host: args.pool_max_idle_per_host.unwrap_or(usize::MAX),
+            http2_only: args.http2_only,
+            http2_keep_alive_interval: args.http2_keep_alive_secs.map(Duration::from_secs),
+            http2_adaptive_window: args.http2_adaptive_window,
+            timeouts: Timeouts {
+                connect: millis(args.connect_timeout_ms),
+                first_byte: millis(args.first_byte_timeout_ms),
+                total: millis(args.total_timeout_ms),
+                idle_body: millis(args.idle_body_timeout_ms),
+            },
+        }
+    }
+}
+
+fn millis(ms: u64) -> Option<Duration> {
+    match ms {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+/// TLS settings for connections to upstream servers.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct UpstreamTls {
+    ca: Vec<Vec<u8>>,
+    identity: Option<(Vec<u8>, Vec<u8>)>,
+    server_name: Option<String>,
+    insecure_skip_verify: bool,
+}
+
+impl UpstreamTls {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust a DER certificate authority. Once any are given, the system trust store is not used.
+    pub fn ca(mut self, cert: Vec<u8>) -> Self {
+        self.ca.push(cert);
+        self
+    }
+
+    /// Present a client certificate for mutual TLS, from a PEM certificate chain and PKCS#8 PEM
+    /// key (see [`crate::tls::read_pkcs8_key`]).
+    pub fn identity(self, cert: Vec<u8>, key: Vec<u8>) -> Self {
+        Self {
+            identity: Some((cert, key)),
+            ..self
+        }
+    }
+
+    /// Send this server name with SNI and verify the upstream certificate against it, instead of
+    /// the upstream host.
+    pub fn server_name(self, server_name: String) -> Self {
+        Self {
+            server_name: Some(server_name),
+            ..self
+        }
+    }
+
+    /// Accept any upstream certificate. Only for development.
+    pub fn insecure_skip_verify(self, insecure_skip_verify: bool) -> Self {
+        Self {
+            insecure_skip_verify,
+            ..self
+        }
+    }
+
+    pub fn connector(&self) -> Result<native_tls::TlsConnector, native_tls::Error> {
+        let mut builder = native_tls::TlsConnector::builder();
+
+        if !self.ca.is_empty() {
+            builder.disable_built_in_roots(true);
+        }
+        for cert in &self.ca {
+            builder.add_root_certificate(native_tls::Certificate::from_der(cert)?);
+        }
+
+        if let Some((cert, key)) = &self.identity {
+            builder.identity(native_tls::Identity::from_pkcs8(cert, key)?);
+        }
+
+        builder
+            .danger_accept_invalid_certs(self.insecure_skip_verify)
+            .danger_accept_invalid_hostnames(self.insecure_skip_verify)
+            .build()
+    }
+}
+
+/// Connects to upstream servers, negotiating TLS for `https` URIs.
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    http: HttpConnector,
+    tls: tokio_native_tls::TlsConnector,
+    server_name: Option<String>,
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = MaybeHttpsStream<TcpStream>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let https = uri.scheme() == Some(&Scheme::HTTPS);
+        let server_name = self.server_name.clone().or_else(|| {
+            uri.host().map(|host| {
+                host.trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .to_string()
+            })
+        });
+        let connecting = self.http.call(uri);
+        let tls = self.tls.clone();
+
+        Box::pin(async move {
+            let tcp = connecting.await?;
+            if !https {
+                return Ok(MaybeHttpsStream::Http(tcp));
+            }
+
+            let server_name = server_name.ok_or("upstream URI has no host")?;
+            Ok(MaybeHttpsStream::Https(
+                tls.connect(&server_name, tcp).await?,
+            ))
+        })
+    }
+}
+
+/// Build a pooled client. It is cheap to clone, and clones share the same connection pool.
+pub fn build(config: &ClientConfig, tls: &UpstreamTls) -> Result<HttpClient, native_tls::Error> {
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    http.set_connect_timeout(config.timeouts.connect);
+
+    let connector = UpstreamConnector {
+        http,
+        tls: tls.connector()?.into(),
+        server_name: tls.server_name.clone(),
+    };
+
+    Ok(Client::builder()
+        .pool_idle_timeout(config.pool_idle_timeout)
+        .pool_max_idle_per_host(config.pool_max_idle_per_host)
+        .http2_only(config.http2_only)
+        .http2_keep_alive_interval(config.http2_keep_alive_interval)
+        .http2_adaptive_window(config.http2_adaptive_window)
+        .build(connector))
+}
+
+type ConnectorKey = (Option<Duration>, UpstreamTls);
+
+/// Pooled clients, one per connect timeout and upstream TLS settings, as both are properties of
+/// the connector.
+#[derive(Clone)]
+pub struct Clients {
+    config: ClientConfig,
+    default: HttpClient,
+    by_connector: Arc<Mutex<HashMap<ConnectorKey, HttpClient>>>,
+}
+
+impl Clients {
+    pub fn new(config: ClientConfig) -> Self {
+        Self {
+            default: build(&config, &UpstreamTls::default())
+                .expect("system TLS configuration is usable"),
+            config,
+            by_connector: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn default_client(&self) -> &HttpClient {
+        &self.default
+    }
+
+    pub fn default_timeouts(&self) -> &Timeouts {
+        &self.config.timeouts
+    }
+
+    /// A client whose connections time out after `connect` and use the `tls` settings.
+    pub fn client(
+        &self,
+        connect: Option<Duration>,
+        tls: Option<&UpstreamTls>,
+    ) -> Result<HttpClient, native_tls::Error> {
+        if connect == self.config.timeouts.connect && tls.is_none() {
+            return Ok(self.default.clone());
+        }
+
+        let key = (connect, tls.cloned().unwrap_or_default());
+        let mut clients = self
+            .by_connector
+            .lock()
+            .expect("client cache lock not poisoned");
+
+        if let Some(client) = clients.get(&key) {
+            return Ok(client.clone());
+        }
+
+        let client = build(
+            &ClientConfig {
+                timeouts: Timeouts {
+                    connect,
+                    ..self.config.timeouts
+                },
+                ..self.config.clone()
+            },
+            &key.1,
+        )?;
+        clients.insert(key, client.clone());
+
+        Ok(client)
+    }
+}
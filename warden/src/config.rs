@@ -0,0 +1,2754 @@
+use crate::action::forwarding::{Cidr, ClientCertHeaders, Forwarding};
+use crate::action::header::{self, HeaderUpdate};
+use crate::action::proxy::{PathUpdate, Proxy, QueryUpdate};
+use crate::action::redirect::Redirect;
+use crate::action::respond::Respond;
+use crate::action::response::{CookieRewrite, LocationRewrite, ResponseUpdate};
+use crate::action::serve_dir::ServeDir;
+use crate::action::upstream::{Balance, HashKey, Upstream, MAX_WEIGHT};
+use crate::action::Action;
+use crate::agent::Rule;
+use crate::circuit::CircuitPolicy;
+use crate::client::UpstreamTls;
+use crate::health::HealthCheck;
+use crate::listener::{ListenerSettings, Protocols};
+use crate::retry::{self, RetryOn, RetryPolicy};
+use crate::timeout::Timeouts;
+use crate::tls::{
+    self, CertPaths, CertResolver, ClientAuth, ClientAuthMode, TlsSettings, TlsVersion,
+};
+use crate::trigger::client_cert::ClientCertTrigger;
+use crate::trigger::header::HeaderTrigger;
+use crate::trigger::host::{HostPattern, HostTrigger};
+use crate::trigger::method::MethodTrigger;
+use crate::trigger::path::{placeholders, PathTemplate, PathTrigger};
+use crate::trigger::query::QueryTrigger;
+use crate::trigger::Trigger;
+use http::header::{HeaderName, HeaderValue};
+use http::uri::{Authority, Scheme};
+use http::{Method, StatusCode};
+use hyper::body::Bytes;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tower::retry::budget::Budget;
+
+/// Errors raised while loading a ruleset file.
+#[derive(Debug)]
+pub enum Error {
+    Io(PathBuf, std::io::Error),
+    UnknownFormat(PathBuf),
+    Parse(String),
+    Rule {
+        index: usize,
+        field: String,
+        message: String,
+    },
+    Listener {
+        index: usize,
+        field: String,
+        message: String,
+    },
+    /// Listeners were added, removed or changed, which takes a restart.
+    ListenersChanged,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(path, err) => write!(f, "cannot read {}: {}", path.display(), err),
+            Self::UnknownFormat(path) => write!(
+                f,
+                "cannot infer format of {} (expected .toml, .yaml, .yml or .json)",
+                path.display()
+            ),
+            Self::Parse(message) => write!(f, "invalid ruleset: {}", message),
+            Self::Rule {
+                index,
+                field,
+                message,
+            } => write!(f, "rule {}, field `{}`: {}", index, field, message),
+            Self::Listener {
+                index,
+                field,
+                message,
+            } => write!(f, "listener {}, field `{}`: {}", index, field, message),
+            Self::ListenersChanged => {
+                write!(f, "listeners differ from those running; restart to apply")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(Self::Toml),
+            "yaml" | "yml" => Some(Self::Yaml),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+/// The contents of a config file.
+pub struct Config {
+    /// Ordered rules for listeners without their own.
+    pub rules: Vec<Rule>,
+    pub listeners: Vec<ListenerConfig>,
+}
+
+/// A listener defined in a config file.
+pub struct ListenerConfig {
+    pub settings: ListenerSettings,
+    /// Ordered rules for this listener, or `None` to use the shared rules.
+    pub rules: Option<Vec<Rule>>,
+}
+
+/// Load rules and listeners from a TOML, YAML or JSON file, picking the format from the file
+/// extension.
+pub fn load(path: &Path) -> Result<Config, Error> {
+    let format = Format::from_path(path).ok_or_else(|| Error::UnknownFormat(path.to_path_buf()))?;
+    let contents = std::fs::read_to_string(path).map_err(|e| Error::Io(path.to_path_buf(), e))?;
+
+    parse(&contents, format)
+}
+
+pub fn parse(contents: &str, format: Format) -> Result<Config, Error> {
+    // Rules and listeners are first read as untyped values so that a malformed one can be
+    // reported by index.
+    let config: ConfigSpec = match format {
+        Format::Toml => toml::from_str(contents).map_err(|e| Error::Parse(e.to_string()))?,
+        Format::Yaml => serde_yaml::from_str(contents).map_err(|e| Error::Parse(e.to_string()))?,
+        Format::Json => serde_json::from_str(contents).map_err(|e| Error::Parse(e.to_string()))?,
+    };
+
+    let rules = build_rules(config.rules).map_err(|(index, field, message)| Error::Rule {
+        index,
+        field,
+        message,
+    })?;
+
+    let listeners = config
+        .listeners
+        .into_iter()
+        .enumerate()
+        .map(|(index, value)| {
+            let spec: ListenerSpec =
+                serde_path_to_error::deserialize(value).map_err(|e| Error::Listener {
+                    index,
+                    field: e.path().to_string(),
+                    message: e.into_inner().to_string(),
+                })?;
+
+            spec.build().map_err(|(field, message)| Error::Listener {
+                index,
+                field,
+                message,
+            })
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(Config { rules, listeners })
+}
+
+/// Build rules, reporting a failure as the rule index, field path and description.
+fn build_rules(values: Vec<serde_json::Value>) -> Result<Vec<Rule>, (usize, String, String)> {
+    values
+        .into_iter()
+        .enumerate()
+        .map(|(index, value)| {
+            let spec: RuleSpec = serde_path_to_error::deserialize(value)
+                .map_err(|e| (index, e.path().to_string(), e.into_inner().to_string()))?;
+
+            spec.build()
+                .map_err(|(field, message)| (index, field, message))
+        })
+        .collect()
+}
+
+/// A conversion failure, as the offending field path and a description.
+type SpecError = (String, String);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigSpec {
+    #[serde(default)]
+    rules: Vec<serde_json::Value>,
+    #[serde(default)]
+    listeners: Vec<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListenerSpec {
+    addr: Option<String>,
+    port: u16,
+    #[serde(default)]
+    protocols: ProtocolsSpec,
+    tls: Option<ListenerTlsSpec>,
+    rules: Option<Vec<serde_json::Value>>,
+}
+
+impl ListenerSpec {
+    fn build(self) -> Result<ListenerConfig, SpecError> {
+        let ip = match self.addr {
+            Some(addr) => {
+                IpAddr::from_str(&addr).map_err(|e| (String::from("addr"), e.to_string()))?
+            }
+            None => IpAddr::from(Ipv6Addr::LOCALHOST),
+        };
+
+        let protocols = self.protocols.build();
+
+        let tls = match self.tls {
+            Some(tls) => Some(tls.build("tls", protocols)?),
+            None => None,
+        };
+
+        let rules = match self.rules {
+            Some(rules) => Some(build_rules(rules).map_err(|(index, field, message)| {
+                (format!("rules[{}].{}", index, field), message)
+            })?),
+            None => None,
+        };
+
+        Ok(ListenerConfig {
+            settings: ListenerSettings {
+                addr: SocketAddr::new(ip, self.port),
+                tls,
+                protocols,
+            },
+            rules,
+        })
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ProtocolsSpec {
+    #[default]
+    Auto,
+    Http1Only,
+    Http2Only,
+}
+
+impl ProtocolsSpec {
+    fn build(self) -> Protocols {
+        match self {
+            Self::Auto => Protocols::Auto,
+            Self::Http1Only => Protocols::Http1Only,
+            Self::Http2Only => Protocols::Http2Only,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListenerTlsSpec {
+    certs: Vec<CertSpec>,
+    min_version: Option<String>,
+    alpn: Option<Vec<String>>,
+    client_ca: Option<PathBuf>,
+    client_auth: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CertSpec {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl ListenerTlsSpec {
+    fn build(self, field: &str, protocols: Protocols) -> Result<TlsSettings, SpecError> {
+        if self.certs.is_empty() {
+            return Err((
+                format!("{}.certs", field),
+                String::from("at least one certificate is required"),
+            ));
+        }
+
+        let min_version = match self.min_version {
+            Some(version) => {
+                TlsVersion::from_str(&version).map_err(|e| (format!("{}.min_version", field), e))?
+            }
+            None => TlsVersion::Tls12,
+        };
+
+        let client_auth = match (self.client_ca, self.client_auth) {
+            (Some(ca), mode) => Some(ClientAuth {
+                ca,
+                mode: match mode {
+                    Some(mode) => ClientAuthMode::from_str(&mode)
+                        .map_err(|e| (format!("{}.client_auth", field), e))?,
+                    None => ClientAuthMode::Require,
+                },
+            }),
+            (None, None) => None,
+            (None, Some(_)) => {
+                return Err((
+                    format!("{}.client_auth", field),
+                    String::from("client_ca is required for client authentication"),
+                ))
+            }
+        };
+
+        let settings = TlsSettings {
+            certs: self
+                .certs
+                .into_iter()
+                .map(|c| CertPaths {
+                    cert: c.cert,
+                    key: c.key,
+                })
+                .collect(),
+            min_version,
+            alpn: self.alpn.unwrap_or_else(|| protocols.alpn()),
+            client_auth,
+        };
+
+        // Fail on unusable certificates now rather than when the listener starts.
+        let resolver = CertResolver::load(&settings.certs)
+            .map_err(|e| (format!("{}.certs", field), e.to_string()))?;
+        settings
+            .server_config(Arc::new(resolver))
+            .map_err(|e| (field.to_string(), e.to_string()))?;
+
+        Ok(settings)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    #[serde(default)]
+    trigger: TriggerSpec,
+    action: ActionSpec,
+}
+
+impl RuleSpec {
+    fn build(self) -> Result<Rule, SpecError> {
+        let trigger = self.trigger.build("trigger")?;
+        let action = self.action.build(&trigger.capture_names())?;
+
+        Ok(Rule::new(trigger, action))
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TriggerSpec {
+    path: Option<PathTriggerSpec>,
+    method: Option<MethodTriggerSpec>,
+    #[serde(default)]
+    headers: Vec<HeaderTriggerSpec>,
+    host: Option<HostTriggerSpec>,
+    query: Option<QueryTriggerSpec>,
+    client_cert: Option<ClientCertTriggerSpec>,
+    all: Option<Vec<TriggerSpec>>,
+    any_of: Option<Vec<TriggerSpec>>,
+    not: Option<Box<TriggerSpec>>,
+}
+
+impl TriggerSpec {
+    /// Build the trigger tree; every trigger given at one level must apply.
+    fn build(self, field: &str) -> Result<Trigger, SpecError> {
+        let mut triggers = Vec::new();
+
+        if let Some(p) = self.path {
+            triggers.push(Trigger::Path(p.build(&format!("{}.path", field))?));
+        }
+
+        if let Some(m) = self.method {
+            triggers.push(Trigger::Method(m.build(&format!("{}.method", field))?));
+        }
+
+        for (i, header) in self.headers.into_iter().enumerate() {
+            let field = format!("{}.headers[{}]", field, i);
+            triggers.push(Trigger::Header(header.build(&field)?));
+        }
+
+        if let Some(h) = self.host {
+            triggers.push(Trigger::Host(h.build(&format!("{}.host", field))?));
+        }
+
+        if let Some(q) = self.query {
+            triggers.push(Trigger::Query(q.build(&format!("{}.query", field))?));
+        }
+
+        if let Some(c) = self.client_cert {
+            triggers.push(Trigger::ClientCert(
+                c.build(&format!("{}.client_cert", field))?,
+            ));
+        }
+
+        if let Some(all) = self.all {
+            let field = format!("{}.all", field);
+            triggers.push(Trigger::All(build_triggers(all, &field)?));
+        }
+
+        if let Some(any_of) = self.any_of {
+            let field = format!("{}.any_of", field);
+            triggers.push(Trigger::AnyOf(build_triggers(any_of, &field)?));
+        }
+
+        if let Some(not) = self.not {
+            triggers.push(Trigger::negate(not.build(&format!("{}.not", field))?));
+        }
+
+        Ok(Trigger::All(triggers))
+    }
+}
+
+fn build_triggers(specs: Vec<TriggerSpec>, field: &str) -> Result<Vec<Trigger>, SpecError> {
+    specs
+        .into_iter()
+        .enumerate()
+        .map(|(i, t)| t.build(&format!("{}[{}]", field, i)))
+        .collect()
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PathTriggerSpec {
+    Any,
+    Exactly(String),
+    Contains(String),
+    Regex(String),
+    Template(String),
+}
+
+impl PathTriggerSpec {
+    fn build(self, field: &str) -> Result<PathTrigger, SpecError> {
+        match self {
+            Self::Any => Ok(PathTrigger::Any),
+            Self::Exactly(path) => Ok(PathTrigger::Exactly(path)),
+            Self::Contains(path) => Ok(PathTrigger::Contains(path)),
+            Self::Regex(reg) => regex::Regex::new(&reg)
+                .map(PathTrigger::Regex)
+                .map_err(|e| (format!("{}.regex", field), e.to_string())),
+            Self::Template(template) => PathTemplate::new(&template)
+                .map(PathTrigger::Template)
+                .map_err(|e| (format!("{}.template", field), e.to_string())),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum MethodTriggerSpec {
+    Any,
+    Exactly(String),
+    OneOf(Vec<String>),
+    NoneOf(Vec<String>),
+}
+
+impl MethodTriggerSpec {
+    fn build(self, field: &str) -> Result<MethodTrigger, SpecError> {
+        match self {
+            Self::Any => Ok(MethodTrigger::Any),
+            Self::Exactly(m) => Ok(MethodTrigger::Exactly(parse_method(
+                &format!("{}.exactly", field),
+                &m,
+            )?)),
+            Self::OneOf(mx) => Ok(MethodTrigger::OneOf(parse_methods(
+                &format!("{}.one_of", field),
+                &mx,
+            )?)),
+            Self::NoneOf(mx) => Ok(MethodTrigger::NoneOf(parse_methods(
+                &format!("{}.none_of", field),
+                &mx,
+            )?)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum HeaderTriggerSpec {
+    Present(String),
+    Absent(String),
+    Exactly { name: String, value: String },
+    Contains { name: String, value: String },
+    Regex { name: String, regex: String },
+    OneOf { name: String, values: Vec<String> },
+}
+
+impl HeaderTriggerSpec {
+    fn build(self, field: &str) -> Result<HeaderTrigger, SpecError> {
+        match self {
+            Self::Present(name) => Ok(HeaderTrigger::Present(parse_header_name(
+                &format!("{}.present", field),
+                &name,
+            )?)),
+            Self::Absent(name) => Ok(HeaderTrigger::Absent(parse_header_name(
+                &format!("{}.absent", field),
+                &name,
+            )?)),
+            Self::Exactly { name, value } => Ok(HeaderTrigger::Exactly(
+                parse_header_name(&format!("{}.exactly.name", field), &name)?,
+                value,
+            )),
+            Self::Contains { name, value } => Ok(HeaderTrigger::Contains(
+                parse_header_name(&format!("{}.contains.name", field), &name)?,
+                value,
+            )),
+            Self::Regex { name, regex } => Ok(HeaderTrigger::Regex(
+                parse_header_name(&format!("{}.regex.name", field), &name)?,
+                regex::Regex::new(&regex)
+                    .map_err(|e| (format!("{}.regex.regex", field), e.to_string()))?,
+            )),
+            Self::OneOf { name, values } => Ok(HeaderTrigger::OneOf(
+                parse_header_name(&format!("{}.one_of.name", field), &name)?,
+                values,
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct HostTriggerSpec {
+    #[serde(flatten)]
+    pattern: HostPatternSpec,
+    port: Option<u16>,
+}
+
+impl HostTriggerSpec {
+    fn build(self, field: &str) -> Result<HostTrigger, SpecError> {
+        let trigger = HostTrigger::new(self.pattern.build(field)?);
+
+        match self.port {
+            Some(port) => Ok(trigger.port(port)),
+            None => Ok(trigger),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum HostPatternSpec {
+    Any,
+    Exactly(String),
+    Wildcard(String),
+    Regex(String),
+}
+
+impl HostPatternSpec {
+    fn build(self, field: &str) -> Result<HostPattern, SpecError> {
+        match self {
+            Self::Any => Ok(HostPattern::Any),
+            Self::Exactly(host) => Ok(HostPattern::Exactly(host)),
+            Self::Wildcard(host) if host.starts_with("*.") => Ok(HostPattern::Wildcard(host)),
+            Self::Wildcard(_) => Err((
+                format!("{}.wildcard", field),
+                String::from("wildcard hosts must start with `*.`"),
+            )),
+            Self::Regex(reg) => regex::Regex::new(&reg)
+                .map(HostPattern::Regex)
+                .map_err(|e| (format!("{}.regex", field), e.to_string())),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum QueryTriggerSpec {
+    Any,
+    Present(String),
+    Absent(String),
+    Exactly { name: String, value: String },
+    Regex { name: String, regex: String },
+    All(Vec<QueryTriggerSpec>),
+}
+
+impl QueryTriggerSpec {
+    fn build(self, field: &str) -> Result<QueryTrigger, SpecError> {
+        match self {
+            Self::Any => Ok(QueryTrigger::Any),
+            Self::Present(name) => Ok(QueryTrigger::Present(name)),
+            Self::Absent(name) => Ok(QueryTrigger::Absent(name)),
+            Self::Exactly { name, value } => Ok(QueryTrigger::Exactly(name, value)),
+            Self::Regex { name, regex } => regex::Regex::new(&regex)
+                .map(|reg| QueryTrigger::Regex(name, reg))
+                .map_err(|e| (format!("{}.regex.regex", field), e.to_string())),
+            Self::All(triggers) => triggers
+                .into_iter()
+                .enumerate()
+                .map(|(i, t)| t.build(&format!("{}.all[{}]", field, i)))
+                .collect::<Result<_, _>>()
+                .map(QueryTrigger::All),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ClientCertTriggerSpec {
+    Present,
+    Absent,
+    Subject(String),
+    San(String),
+    Fingerprint(Vec<String>),
+}
+
+impl ClientCertTriggerSpec {
+    fn build(self, field: &str) -> Result<ClientCertTrigger, SpecError> {
+        match self {
+            Self::Present => Ok(ClientCertTrigger::Present),
+            Self::Absent => Ok(ClientCertTrigger::Absent),
+            Self::Subject(reg) => regex::Regex::new(&reg)
+                .map(ClientCertTrigger::Subject)
+                .map_err(|e| (format!("{}.subject", field), e.to_string())),
+            Self::San(san) => Ok(ClientCertTrigger::San(san)),
+            Self::Fingerprint(fingerprints) => fingerprints
+                .iter()
+                .enumerate()
+                .map(|(i, f)| {
+                    let hex = f.replace(':', "").to_ascii_lowercase();
+                    match hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                        true => Ok(hex),
+                        false => Err((
+                            format!("{}.fingerprint[{}]", field, i),
+                            String::from("expected a SHA-256 fingerprint in hex"),
+                        )),
+                    }
+                })
+                .collect::<Result<_, _>>()
+                .map(ClientCertTrigger::Fingerprint),
+        }
+    }
+}
+
+/// The params header templates may refer to: the built-in values and the trigger's captures.
+fn header_params<'a>(captures: &[&'a str]) -> Vec<&'a str> {
+    header::BUILT_IN_PARAMS
+        .into_iter()
+        .chain(captures.iter().copied())
+        .collect()
+}
+
+/// Check that every placeholder in `template` names one of `params`.
+fn check_template(field: &str, template: &str, params: &[&str]) -> Result<(), SpecError> {
+    match placeholders(template)
+        .into_iter()
+        .find(|name| !params.contains(name))
+    {
+        Some(name) => Err((
+            field.to_string(),
+            format!("unknown placeholder {{{}}}", name),
+        )),
+        None => Ok(()),
+    }
+}
+
+fn parse_header_name(field: &str, name: &str) -> Result<HeaderName, SpecError> {
+    HeaderName::from_str(name).map_err(|e| (field.to_string(), e.to_string()))
+}
+
+fn parse_method(field: &str, method: &str) -> Result<Method, SpecError> {
+    Method::from_str(method).map_err(|e| (field.to_string(), e.to_string()))
+}
+
+fn parse_methods(field: &str, methods: &[String]) -> Result<Vec<Method>, SpecError> {
+    methods
+        .iter()
+        .enumerate()
+        .map(|(i, m)| parse_method(&format!("{}[{}]", field, i), m))
+        .collect()
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ActionSpec {
+    Proxy(Box<ProxySpec>),
+    Redirect(RedirectSpec),
+    Respond(RespondSpec),
+    ServeDir(ServeDirSpec),
+}
+
+impl ActionSpec {
+    /// Build the action; `captures` are the params the rule's trigger captures, which templates
+    /// may refer to.
+    fn build(self, captures: &[&str]) -> Result<Action, SpecError> {
+        match self {
+            Self::Proxy(proxy) => proxy.build(captures).map(|p| Action::Proxy(Box::new(p))),
+            Self::Redirect(redirect) => redirect
+                .build("action.redirect", captures)
+                .map(Action::Redirect),
+            Self::Respond(respond) => respond.build("action.respond").map(Action::Respond),
+            Self::ServeDir(serve_dir) => serve_dir
+                .build("action.serve_dir", captures)
+                .map(Action::ServeDir),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProxySpec {
+    scheme: Option<String>,
+    host: Option<String>,
+    port: Option<u16>,
+    #[serde(default)]
+    upstreams: Vec<UpstreamSpec>,
+    balance: Option<BalanceSpec>,
+    health_check: Option<HealthCheckSpec>,
+    circuit_breaker: Option<CircuitPolicySpec>,
+    retry: Option<RetryPolicySpec>,
+    timeouts: Option<TimeoutsSpec>,
+    tls: Option<UpstreamTlsSpec>,
+    path: Option<PathUpdateSpec>,
+    query: Option<QueryUpdateSpec>,
+    preserve_host: Option<bool>,
+    forwarded_headers: Option<bool>,
+    #[serde(default)]
+    trusted_proxies: Vec<String>,
+    client_cert_headers: Option<ClientCertHeadersSpec>,
+    #[serde(default)]
+    headers: Vec<HeaderUpdateSpec>,
+    response: Option<ResponseUpdateSpec>,
+}
+
+impl ProxySpec {
+    fn build(self, captures: &[&str]) -> Result<Proxy, SpecError> {
+        let header_params = header_params(captures);
+
+        let builder = match (self.scheme, self.host) {
+            (Some(scheme), Some(host)) => {
+                validate_upstream("action.proxy", &scheme, &host)?;
+                Proxy::builder().scheme(scheme).host(host)
+            }
+            (None, None) => Proxy::builder(),
+            _ => {
+                return Err((
+                    String::from("action.proxy"),
+                    String::from("scheme and host must be given together"),
+                ))
+            }
+        };
+
+        let builder = match self.port {
+            Some(port) => builder.port(port),
+            None => builder,
+        };
+
+        let mut builder = builder;
+        for (i, upstream) in self.upstreams.into_iter().enumerate() {
+            let field = format!("action.proxy.upstreams[{}]", i);
+            builder = builder.upstream(upstream.build(&field)?);
+        }
+
+        let builder = match self.balance {
+            Some(balance) => builder.balance(balance.build("action.proxy.balance")?),
+            None => builder,
+        };
+
+        let builder = match self.health_check {
+            Some(check) => builder.health_check(check.build("action.proxy.health_check")?),
+            None => builder,
+        };
+
+        let builder = match self.circuit_breaker {
+            Some(policy) => builder.circuit_breaker(policy.build("action.proxy.circuit_breaker")?),
+            None => builder,
+        };
+
+        let builder = match self.retry {
+            Some(retry) => builder.retry(retry.build("action.proxy.retry")?),
+            None => builder,
+        };
+
+        let builder = match self.timeouts {
+            Some(timeouts) => builder.timeouts(timeouts.build()),
+            None => builder,
+        };
+
+        let builder = match self.tls {
+            Some(tls) => builder.tls(tls.build("action.proxy.tls")?),
+            None => builder,
+        };
+
+        let builder = match self.path {
+            Some(path) => builder.path(path.build("action.proxy.path", captures)?),
+            None => builder,
+        };
+
+        let builder = match self.query {
+            Some(query) => builder.query(query.build()),
+            None => builder,
+        };
+
+        let defaults = Forwarding::default();
+        let trusted_proxies = self
+            .trusted_proxies
+            .iter()
+            .enumerate()
+            .map(|(i, net)| {
+                Cidr::from_str(net).map_err(|e| (format!("action.proxy.trusted_proxies[{}]", i), e))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let mut builder = builder.forwarding(Forwarding {
+            trusted_proxies,
+            preserve_host: self.preserve_host.unwrap_or(defaults.preserve_host),
+            headers: self.forwarded_headers.unwrap_or(defaults.headers),
+            client_cert: match self.client_cert_headers {
+                Some(headers) => headers.build("action.proxy.client_cert_headers")?,
+                None => defaults.client_cert,
+            },
+        });
+
+        for (i, update) in self.headers.into_iter().enumerate() {
+            let field = format!("action.proxy.headers[{}]", i);
+            builder = builder.header(update.build(&field, &header_params)?);
+        }
+
+        let builder = match self.response {
+            Some(response) => {
+                builder.response(response.build("action.proxy.response", &header_params)?)
+            }
+            None => builder,
+        };
+
+        builder.build().ok_or_else(|| {
+            (
+                String::from("action.proxy"),
+                String::from("scheme and host, or upstreams, are required"),
+            )
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClientCertHeadersSpec {
+    subject: Option<String>,
+    san: Option<String>,
+    fingerprint: Option<String>,
+}
+
+impl ClientCertHeadersSpec {
+    fn build(self, field: &str) -> Result<ClientCertHeaders, SpecError> {
+        let header = |name: &str, value: Option<String>| {
+            value
+                .map(|v| parse_header_name(&format!("{}.{}", field, name), &v))
+                .transpose()
+        };
+
+        Ok(ClientCertHeaders {
+            subject: header("subject", self.subject)?,
+            san: header("san", self.san)?,
+            fingerprint: header("fingerprint", self.fingerprint)?,
+        })
+    }
+}
+
+fn validate_upstream(field: &str, scheme: &str, host: &str) -> Result<(), SpecError> {
+    Scheme::from_str(scheme).map_err(|e| (format!("{}.scheme", field), e.to_string()))?;
+    Authority::from_str(host).map_err(|e| (format!("{}.host", field), e.to_string()))?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamSpec {
+    scheme: String,
+    host: String,
+    port: Option<u16>,
+    weight: Option<u32>,
+}
+
+impl UpstreamSpec {
+    fn build(self, field: &str) -> Result<Upstream, SpecError> {
+        validate_upstream(field, &self.scheme, &self.host)?;
+
+        let upstream = Upstream::new(self.scheme, self.host, self.port);
+
+        match self.weight {
+            Some(weight) if !(1..=MAX_WEIGHT).contains(&weight) => Err((
+                format!("{}.weight", field),
+                format!("weight must be between 1 and {}", MAX_WEIGHT),
+            )),
+            Some(weight) => Ok(upstream.weight(weight)),
+            None => Ok(upstream),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HealthCheckSpec {
+    path: Option<String>,
+    expected_status: Option<u16>,
+    interval_secs: Option<u64>,
+    timeout_secs: Option<u64>,
+    rise: Option<u32>,
+    fall: Option<u32>,
+}
+
+impl HealthCheckSpec {
+    fn build(self, field: &str) -> Result<HealthCheck, SpecError> {
+        let defaults = HealthCheck::default();
+
+        let path = self.path.unwrap_or(defaults.path);
+        http::uri::PathAndQuery::from_str(&path)
+            .map_err(|e| (format!("{}.path", field), e.to_string()))?;
+
+        let expected_status = match self.expected_status {
+            Some(status) => Some(
+                StatusCode::from_u16(status)
+                    .map_err(|e| (format!("{}.expected_status", field), e.to_string()))?,
+            ),
+            None => None,
+        };
+
+        let positive = |name: &str, value: Option<u64>, default: u64| match value {
+            Some(0) => Err((
+                format!("{}.{}", field, name),
+                String::from("must be positive"),
+            )),
+            Some(v) => Ok(v),
+            None => Ok(default),
+        };
+
+        Ok(HealthCheck {
+            path,
+            expected_status,
+            interval: Duration::from_secs(positive(
+                "interval_secs",
+                self.interval_secs,
+                defaults.interval.as_secs(),
+            )?),
+            timeout: Duration::from_secs(positive(
+                "timeout_secs",
+                self.timeout_secs,
+                defaults.timeout.as_secs(),
+            )?),
+            rise: positive("rise", self.rise.map(u64::from), u64::from(defaults.rise))? as u32,
+            fall: positive("fall", self.fall.map(u64::from), u64::from(defaults.fall))? as u32,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CircuitPolicySpec {
+    failure_threshold: Option<u32>,
+    cool_down_secs: Option<u64>,
+    half_open_trials: Option<u32>,
+}
+
+impl CircuitPolicySpec {
+    fn build(self, field: &str) -> Result<CircuitPolicy, SpecError> {
+        let defaults = CircuitPolicy::default();
+
+        let positive = |name: &str, value: Option<u32>, default: u32| match value {
+            Some(0) => Err((
+                format!("{}.{}", field, name),
+                String::from("must be positive"),
+            )),
+            Some(v) => Ok(v),
+            None => Ok(default),
+        };
+
+        Ok(CircuitPolicy {
+            failure_threshold: positive(
+                "failure_threshold",
+                self.failure_threshold,
+                defaults.failure_threshold,
+            )?,
+            cool_down: self
+                .cool_down_secs
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.cool_down),
+            half_open_trials: positive(
+                "half_open_trials",
+                self.half_open_trials,
+                defaults.half_open_trials,
+            )?,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TimeoutsSpec {
+    connect_ms: Option<u64>,
+    first_byte_ms: Option<u64>,
+    total_ms: Option<u64>,
+    idle_body_ms: Option<u64>,
+}
+
+impl TimeoutsSpec {
+    /// 0 disables a timeout, overriding the global default.
+    fn build(self) -> Timeouts {
+        Timeouts {
+            connect: self.connect_ms.map(Duration::from_millis),
+            first_byte: self.first_byte_ms.map(Duration::from_millis),
+            total: self.total_ms.map(Duration::from_millis),
+            idle_body: self.idle_body_ms.map(Duration::from_millis),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamTlsSpec {
+    ca: Option<PathBuf>,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    server_name: Option<String>,
+    #[serde(default)]
+    insecure_skip_verify: bool,
+}
+
+impl UpstreamTlsSpec {
+    fn build(self, field: &str) -> Result<UpstreamTls, SpecError> {
+        let mut tls = UpstreamTls::new().insecure_skip_verify(self.insecure_skip_verify);
+
+        if let Some(path) = self.ca {
+            let certs =
+                tls::read_certs(&path).map_err(|e| (format!("{}.ca", field), e.to_string()))?;
+            for cert in certs {
+                tls = tls.ca(cert.0);
+            }
+        }
+
+        let tls = match (self.cert, self.key) {
+            (Some(cert), Some(key)) => {
+                let cert =
+                    std::fs::read(&cert).map_err(|e| (format!("{}.cert", field), e.to_string()))?;
+                let key = tls::read_pkcs8_key(&key)
+                    .map_err(|e| (format!("{}.key", field), e.to_string()))?;
+                tls.identity(cert, key)
+            }
+            (None, None) => tls,
+            _ => {
+                return Err((
+                    field.to_string(),
+                    String::from("cert and key must be given together"),
+                ))
+            }
+        };
+
+        let tls = match self.server_name {
+            Some(name) if name.is_empty() => {
+                return Err((
+                    format!("{}.server_name", field),
+                    String::from("must not be empty"),
+                ))
+            }
+            Some(name) => tls.server_name(name),
+            None => tls,
+        };
+
+        tls.connector()
+            .map_err(|e| (field.to_string(), e.to_string()))?;
+
+        Ok(tls)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RetryPolicySpec {
+    max_attempts: Option<u32>,
+    retry_on: Option<Vec<RetryOnSpec>>,
+    methods: Option<MethodTriggerSpec>,
+    backoff_base_ms: Option<u64>,
+    backoff_max_ms: Option<u64>,
+    max_body_bytes: Option<usize>,
+    budget: Option<RetryBudgetSpec>,
+}
+
+impl RetryPolicySpec {
+    fn build(self, field: &str) -> Result<RetryPolicy, SpecError> {
+        let defaults = RetryPolicy::default();
+
+        let max_attempts = match self.max_attempts {
+            Some(0) => {
+                return Err((
+                    format!("{}.max_attempts", field),
+                    String::from("must be positive"),
+                ))
+            }
+            Some(n) => n,
+            None => defaults.max_attempts,
+        };
+
+        let retry_on = match self.retry_on {
+            Some(retry_on) => retry_on
+                .into_iter()
+                .enumerate()
+                .map(|(i, r)| r.build(&format!("{}.retry_on[{}]", field, i)))
+                .collect::<Result<_, _>>()?,
+            None => defaults.retry_on,
+        };
+
+        let methods = match self.methods {
+            Some(methods) => methods.build(&format!("{}.methods", field))?,
+            None => defaults.methods,
+        };
+
+        let budget = match self.budget {
+            Some(budget) => budget.build(&format!("{}.budget", field))?,
+            None => defaults.budget,
+        };
+
+        Ok(RetryPolicy {
+            max_attempts,
+            retry_on,
+            methods,
+            backoff_base: self
+                .backoff_base_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.backoff_base),
+            backoff_max: self
+                .backoff_max_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.backoff_max),
+            max_body_bytes: self.max_body_bytes.unwrap_or(defaults.max_body_bytes),
+            budget,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RetryOnSpec {
+    ConnectError,
+    Timeout,
+    Status(u16),
+}
+
+impl RetryOnSpec {
+    fn build(self, field: &str) -> Result<RetryOn, SpecError> {
+        match self {
+            Self::ConnectError => Ok(RetryOn::ConnectError),
+            Self::Timeout => Ok(RetryOn::Timeout),
+            Self::Status(status) => StatusCode::from_u16(status)
+                .map(RetryOn::Status)
+                .map_err(|e| (format!("{}.status", field), e.to_string())),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RetryBudgetSpec {
+    ttl_secs: Option<u64>,
+    min_per_sec: Option<u32>,
+    retry_percent: Option<f32>,
+}
+
+impl RetryBudgetSpec {
+    fn build(self, field: &str) -> Result<Budget, SpecError> {
+        let ttl = self.ttl_secs.unwrap_or(retry::BUDGET_TTL_SECS);
+        if !(1..=60).contains(&ttl) {
+            return Err((
+                format!("{}.ttl_secs", field),
+                String::from("must be between 1 and 60"),
+            ));
+        }
+
+        let retry_percent = self.retry_percent.unwrap_or(retry::BUDGET_RETRY_PERCENT);
+        if !(0.0..=1000.0).contains(&retry_percent) {
+            return Err((
+                format!("{}.retry_percent", field),
+                String::from("must be between 0 and 1000"),
+            ));
+        }
+
+        Ok(Budget::new(
+            Duration::from_secs(ttl),
+            self.min_per_sec.unwrap_or(retry::BUDGET_MIN_PER_SEC),
+            retry_percent,
+        ))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BalanceSpec {
+    RoundRobin,
+    WeightedRoundRobin,
+    LeastOutstanding,
+    RandomTwoChoices,
+    ConsistentHash(HashKeySpec),
+}
+
+impl BalanceSpec {
+    fn build(self, field: &str) -> Result<Balance, SpecError> {
+        match self {
+            Self::RoundRobin => Ok(Balance::RoundRobin),
+            Self::WeightedRoundRobin => Ok(Balance::WeightedRoundRobin),
+            Self::LeastOutstanding => Ok(Balance::LeastOutstanding),
+            Self::RandomTwoChoices => Ok(Balance::RandomTwoChoices),
+            Self::ConsistentHash(key) => key.build(&format!("{}.consistent_hash", field)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum HashKeySpec {
+    Header(String),
+    Cookie(String),
+    ClientIp,
+}
+
+impl HashKeySpec {
+    fn build(self, field: &str) -> Result<Balance, SpecError> {
+        let key = match self {
+            Self::Header(name) => {
+                HashKey::Header(parse_header_name(&format!("{}.header", field), &name)?)
+            }
+            Self::Cookie(name) => HashKey::Cookie(name),
+            Self::ClientIp => HashKey::ClientIp,
+        };
+
+        Ok(Balance::ConsistentHash(key))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PathUpdateSpec {
+    Replace(String),
+    Prepend(String),
+    Append(String),
+    Template(String),
+    StripPrefix(String),
+    RegexReplace {
+        pattern: String,
+        replacement: String,
+    },
+}
+
+impl PathUpdateSpec {
+    fn build(self, field: &str, captures: &[&str]) -> Result<PathUpdate, SpecError> {
+        match self {
+            Self::Replace(p) => Ok(PathUpdate::Replace(p)),
+            Self::Prepend(p) => Ok(PathUpdate::Prepend(p)),
+            Self::Append(p) => Ok(PathUpdate::Append(p)),
+            Self::Template(t) => {
+                check_template(&format!("{}.template", field), &t, captures)?;
+                Ok(PathUpdate::Template(t))
+            }
+            Self::StripPrefix(p) => Ok(PathUpdate::StripPrefix(p)),
+            Self::RegexReplace {
+                pattern,
+                replacement,
+            } => regex::Regex::new(&pattern)
+                .map(|pattern| PathUpdate::RegexReplace {
+                    pattern,
+                    replacement,
+                })
+                .map_err(|e| (format!("{}.regex_replace.pattern", field), e.to_string())),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum QueryUpdateSpec {
+    Replace(Vec<(String, String)>),
+    Merge(Vec<(String, String)>),
+}
+
+impl QueryUpdateSpec {
+    fn build(self) -> QueryUpdate {
+        match self {
+            Self::Replace(params) => QueryUpdate::Replace(params),
+            Self::Merge(params) => QueryUpdate::Merge(params),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum HeaderUpdateSpec {
+    Set { name: String, value: String },
+    Append { name: String, value: String },
+    Remove(String),
+    RemoveMatching(String),
+    Rename { from: String, to: String },
+}
+
+impl HeaderUpdateSpec {
+    /// Build the update; values may refer to `params`.
+    fn build(self, field: &str, params: &[&str]) -> Result<HeaderUpdate, SpecError> {
+        match self {
+            Self::Set { name, value } => {
+                check_template(&format!("{}.set.value", field), &value, params)?;
+                Ok(HeaderUpdate::Set(
+                    parse_header_name(&format!("{}.set.name", field), &name)?,
+                    value,
+                ))
+            }
+            Self::Append { name, value } => {
+                check_template(&format!("{}.append.value", field), &value, params)?;
+                Ok(HeaderUpdate::Append(
+                    parse_header_name(&format!("{}.append.name", field), &name)?,
+                    value,
+                ))
+            }
+            Self::Remove(name) => Ok(HeaderUpdate::Remove(parse_header_name(
+                &format!("{}.remove", field),
+                &name,
+            )?)),
+            Self::RemoveMatching(pattern) => regex::Regex::new(&pattern)
+                .map(HeaderUpdate::RemoveMatching)
+                .map_err(|e| (format!("{}.remove_matching", field), e.to_string())),
+            Self::Rename { from, to } => Ok(HeaderUpdate::Rename(
+                parse_header_name(&format!("{}.rename.from", field), &from)?,
+                parse_header_name(&format!("{}.rename.to", field), &to)?,
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ResponseUpdateSpec {
+    status: Option<u16>,
+    #[serde(default)]
+    locations: Vec<LocationRewriteSpec>,
+    #[serde(default)]
+    cookies: Vec<CookieRewriteSpec>,
+    #[serde(default)]
+    headers: Vec<HeaderUpdateSpec>,
+}
+
+impl ResponseUpdateSpec {
+    /// Build the update; header templates may refer to `params`.
+    fn build(self, field: &str, params: &[&str]) -> Result<ResponseUpdate, SpecError> {
+        let mut update = match self.status {
+            Some(status) => ResponseUpdate::new().status(
+                StatusCode::from_u16(status)
+                    .map_err(|e| (format!("{}.status", field), e.to_string()))?,
+            ),
+            None => ResponseUpdate::new(),
+        };
+
+        for (i, location) in self.locations.into_iter().enumerate() {
+            update = update.location(match location {
+                LocationRewriteSpec::Upstream => LocationRewrite::Upstream,
+                LocationRewriteSpec::Prefix { from, to } => {
+                    check_template(
+                        &format!("{}.locations[{}].prefix.to", field, i),
+                        &to,
+                        params,
+                    )?;
+                    LocationRewrite::Prefix { from, to }
+                }
+            });
+        }
+
+        for (i, cookie) in self.cookies.into_iter().enumerate() {
+            update = update.cookie(match cookie {
+                CookieRewriteSpec::Domain { from, to } => {
+                    check_template(&format!("{}.cookies[{}].domain.to", field, i), &to, params)?;
+                    CookieRewrite::Domain { from, to }
+                }
+                CookieRewriteSpec::Path { from, to } => CookieRewrite::Path { from, to },
+            });
+        }
+
+        for (i, header) in self.headers.into_iter().enumerate() {
+            let field = format!("{}.headers[{}]", field, i);
+            update = update.header(header.build(&field, params)?);
+        }
+
+        Ok(update)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum LocationRewriteSpec {
+    Upstream,
+    Prefix { from: String, to: String },
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum CookieRewriteSpec {
+    Domain { from: String, to: String },
+    Path { from: String, to: String },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RedirectSpec {
+    status: Option<u16>,
+    scheme: Option<String>,
+    host: Option<String>,
+    path: Option<PathUpdateSpec>,
+    query: Option<QueryUpdateSpec>,
+}
+
+impl RedirectSpec {
+    fn build(self, field: &str, captures: &[&str]) -> Result<Redirect, SpecError> {
+        let status = StatusCode::from_u16(self.status.unwrap_or(302))
+            .map_err(|e| (format!("{}.status", field), e.to_string()))?;
+
+        let redirect = Redirect::new(status).ok_or_else(|| {
+            (
+                format!("{}.status", field),
+                String::from("must be one of 301, 302, 307 or 308"),
+            )
+        })?;
+
+        let redirect = match self.scheme {
+            Some(scheme) => {
+                Scheme::from_str(&scheme)
+                    .map_err(|e| (format!("{}.scheme", field), e.to_string()))?;
+                redirect.scheme(scheme)
+            }
+            None => redirect,
+        };
+
+        let redirect = match self.host {
+            Some(host) => {
+                Authority::from_str(&host)
+                    .map_err(|e| (format!("{}.host", field), e.to_string()))?;
+                redirect.host(host)
+            }
+            None => redirect,
+        };
+
+        let redirect = match self.path {
+            Some(path) => redirect.path(path.build(&format!("{}.path", field), captures)?),
+            None => redirect,
+        };
+
+        Ok(match self.query {
+            Some(query) => redirect.query(query.build()),
+            None => redirect,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RespondSpec {
+    status: Option<u16>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    body: Option<String>,
+    /// Read when the ruleset is loaded; relative paths are resolved from the working directory.
+    body_file: Option<PathBuf>,
+}
+
+impl RespondSpec {
+    fn build(self, field: &str) -> Result<Respond, SpecError> {
+        let status = StatusCode::from_u16(self.status.unwrap_or(200))
+            .map_err(|e| (format!("{}.status", field), e.to_string()))?;
+
+        let mut respond = Respond::new(status);
+        for (name, value) in self.headers {
+            let field = format!("{}.headers.{}", field, name);
+            let value =
+                HeaderValue::from_str(&value).map_err(|e| (field.clone(), e.to_string()))?;
+            respond = respond.header(parse_header_name(&field, &name)?, value);
+        }
+
+        match (self.body, self.body_file) {
+            (Some(body), None) => Ok(respond.body(
+                Bytes::from(body),
+                HeaderValue::from_static("text/plain; charset=utf-8"),
+            )),
+            (None, Some(path)) => {
+                let body = std::fs::read(&path)
+                    .map_err(|e| (format!("{}.body_file", field), e.to_string()))?;
+                let mime = mime_guess::from_path(&path).first_or_octet_stream();
+                let content_type =
+                    HeaderValue::from_str(mime.as_ref()).expect("MIME types are valid headers");
+
+                Ok(respond.body(Bytes::from(body), content_type))
+            }
+            (None, None) => Ok(respond),
+            (Some(_), Some(_)) => Err((
+                field.to_string(),
+                String::from("body and body_file are mutually exclusive"),
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ServeDirSpec {
+    dir: PathBuf,
+    index: Option<bool>,
+    #[serde(default)]
+    precompressed: Vec<PrecompressedSpec>,
+    path: Option<PathUpdateSpec>,
+    fallback: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PrecompressedSpec {
+    Gzip,
+    Br,
+}
+
+impl ServeDirSpec {
+    fn build(self, field: &str, captures: &[&str]) -> Result<ServeDir, SpecError> {
+        if !self.dir.is_dir() {
+            return Err((
+                format!("{}.dir", field),
+                format!("{} is not a directory", self.dir.display()),
+            ));
+        }
+
+        let mut serve_dir = ServeDir::new(&self.dir).index(self.index.unwrap_or(true));
+
+        for encoding in self.precompressed {
+            serve_dir = match encoding {
+                PrecompressedSpec::Gzip => serve_dir.precompressed_gzip(),
+                PrecompressedSpec::Br => serve_dir.precompressed_br(),
+            };
+        }
+
+        let serve_dir = match self.path {
+            Some(path) => serve_dir.path(path.build(&format!("{}.path", field), captures)?),
+            None => serve_dir,
+        };
+
+        match self.fallback {
+            Some(fallback) if !fallback.is_file() => Err((
+                format!("{}.fallback", field),
+                format!("{} is not a file", fallback.display()),
+            )),
+            Some(fallback) => Ok(serve_dir.fallback(fallback)),
+            None => Ok(serve_dir),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit::CircuitState;
+
+    fn mk_req(method: http::Method, uri: &str) -> http::Request<()> {
+        http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(())
+            .unwrap()
+    }
+
+    /// Parse only the shared rules.
+    fn parse(contents: &str, format: Format) -> Result<Vec<Rule>, Error> {
+        super::parse(contents, format).map(|config| config.rules)
+    }
+
+    fn rule_err(res: Result<Vec<Rule>, Error>) -> (usize, String) {
+        match res {
+            Err(Error::Rule { index, field, .. }) => (index, field),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("ruleset unexpectedly valid"),
+        }
+    }
+
+    #[test]
+    fn parse_toml() {
+        let contents = r#"
+            [[rules]]
+            trigger = { path = { regex = "^/api/" }, method = { one_of = ["GET", "POST"] } }
+            action.proxy = { scheme = "http", host = "api.internal", port = 8080, path = { prepend = "/v1" } }
+
+            [[rules]]
+            action.proxy = { scheme = "https", host = "www.internal", query = { merge = [["a", "b"]] } }
+        "#;
+
+        let rules = parse(contents, Format::Toml).unwrap();
+        assert_eq!(rules.len(), 2, "All rules are loaded in order");
+
+        let req = mk_req(http::Method::GET, "https://foo.com/api/x");
+        assert!(
+            rules[0].applies(&req),
+            "Triggers are mapped from the ruleset"
+        );
+
+        let req = mk_req(http::Method::DELETE, "https://foo.com/api/x");
+        assert!(
+            !rules[0].applies(&req),
+            "Triggers are mapped from the ruleset"
+        );
+        assert!(
+            rules[1].applies(&req),
+            "Omitted triggers apply to any request"
+        );
+
+        let req = mk_req(http::Method::GET, "https://foo.com/api/x?c=d");
+        let downstream = rules[0].transform_req(req).unwrap();
+        assert_eq!(
+            downstream.uri().to_string(),
+            "http://api.internal:8080/v1/api/x?c=d",
+            "Proxy actions are mapped from the ruleset"
+        );
+    }
+
+    #[test]
+    fn parse_yaml() {
+        let contents = r#"
+            rules:
+              - trigger:
+                  path:
+                    exactly: /health
+                  method: any
+                action:
+                  proxy:
+                    scheme: http
+                    host: health.internal
+                    path:
+                      replace: /status
+        "#;
+
+        let rules = parse(contents, Format::Yaml).unwrap();
+        let req = mk_req(http::Method::GET, "https://foo.com/health");
+        let downstream = rules[0].transform_req(req).unwrap();
+        assert_eq!(
+            downstream.uri().to_string(),
+            "http://health.internal/status",
+            "YAML rulesets are supported"
+        );
+    }
+
+    #[test]
+    fn parse_json() {
+        let contents = r#"{
+            "rules": [
+                {
+                    "trigger": { "method": { "none_of": ["DELETE"] } },
+                    "action": { "proxy": { "scheme": "http", "host": "a.internal" } }
+                }
+            ]
+        }"#;
+
+        let rules = parse(contents, Format::Json).unwrap();
+        let req = mk_req(http::Method::DELETE, "https://foo.com/");
+        assert!(!rules[0].applies(&req), "JSON rulesets are supported");
+    }
+
+    #[test]
+    fn parse_header_triggers() {
+        let contents = r#"
+            [[rules]]
+            trigger.headers = [
+                { exactly = { name = "X-Tenant", value = "acme" } },
+                { regex = { name = "Accept-Version", regex = "^v2" } },
+            ]
+            action.proxy = { scheme = "http", host = "acme.internal" }
+        "#;
+
+        let rules = parse(contents, Format::Toml).unwrap();
+
+        let req = http::Request::builder()
+            .uri("https://foo.com/")
+            .header("X-Tenant", "acme")
+            .header("Accept-Version", "v2.1")
+            .body(())
+            .unwrap();
+        assert!(
+            rules[0].applies(&req),
+            "Header triggers are mapped from the ruleset"
+        );
+
+        let req = http::Request::builder()
+            .uri("https://foo.com/")
+            .header("X-Tenant", "acme")
+            .body(())
+            .unwrap();
+        assert!(!rules[0].applies(&req), "All header triggers must apply");
+
+        let bad_name = r#"
+            [[rules]]
+            trigger.headers = [{ present = "X-Tenant" }, { absent = "Not A Header" }]
+            action.proxy = { scheme = "http", host = "a.internal" }
+        "#;
+        let (_, field) = rule_err(parse(bad_name, Format::Toml));
+        assert_eq!(
+            field, "trigger.headers[1].absent",
+            "Errors point to the offending field"
+        );
+    }
+
+    #[test]
+    fn parse_host_triggers() {
+        let contents = r#"
+            rules:
+              - trigger:
+                  host:
+                    wildcard: "*.foo.com"
+                    port: 8080
+                action:
+                  proxy: { scheme: http, host: sites.internal }
+              - trigger:
+                  host:
+                    wildcard: foo.com
+                action:
+                  proxy: { scheme: http, host: sites.internal }
+        "#;
+
+        let (index, field) = rule_err(parse(contents, Format::Yaml));
+        assert_eq!(index, 1, "Errors point to the offending rule");
+        assert_eq!(
+            field, "trigger.host.wildcard",
+            "Errors point to the offending field"
+        );
+
+        let contents = r#"
+            [[rules]]
+            trigger.host = { wildcard = "*.foo.com", port = 8080 }
+            action.proxy = { scheme = "http", host = "sites.internal" }
+        "#;
+
+        let rules = parse(contents, Format::Toml).unwrap();
+
+        let req = mk_req(http::Method::GET, "http://www.foo.com:8080/");
+        assert!(
+            rules[0].applies(&req),
+            "Host triggers are mapped from the ruleset"
+        );
+
+        let req = mk_req(http::Method::GET, "http://www.foo.com/");
+        assert!(
+            !rules[0].applies(&req),
+            "Host trigger ports are mapped from the ruleset"
+        );
+    }
+
+    #[test]
+    fn parse_query_triggers() {
+        let contents = r#"
+            [[rules]]
+            trigger.query.all = [{ present = "debug" }, { regex = { name = "id", regex = "^[0-9]+$" } }]
+            action.proxy = { scheme = "http", host = "debug.internal" }
+
+            [[rules]]
+            trigger.query.all = [{ absent = "debug" }, { regex = { name = "id", regex = "(" } }]
+            action.proxy = { scheme = "http", host = "debug.internal" }
+        "#;
+
+        let (index, field) = rule_err(parse(contents, Format::Toml));
+        assert_eq!(index, 1, "Errors point to the offending rule");
+        assert_eq!(
+            field, "trigger.query.all[1].regex.regex",
+            "Errors point to the offending field"
+        );
+
+        let contents = r#"
+            [[rules]]
+            trigger.query.all = [{ present = "debug" }, { regex = { name = "id", regex = "^[0-9]+$" } }]
+            action.proxy = { scheme = "http", host = "debug.internal" }
+        "#;
+
+        let rules = parse(contents, Format::Toml).unwrap();
+
+        let req = mk_req(http::Method::GET, "http://foo.com/?debug=1&id=12");
+        assert!(
+            rules[0].applies(&req),
+            "Query triggers are mapped from the ruleset"
+        );
+
+        let req = mk_req(http::Method::GET, "http://foo.com/?debug=1&id=x");
+        assert!(
+            !rules[0].applies(&req),
+            "Query triggers are mapped from the ruleset"
+        );
+    }
+
+    #[test]
+    fn parse_composite_triggers() {
+        let contents = r#"
+            [[rules]]
+            trigger.not.path.exactly = "/health"
+            trigger.any_of = [
+                { path = { exactly = "/a" } },
+                { path = { exactly = "/b" }, method = { exactly = "POST" } },
+            ]
+            action.proxy = { scheme = "http", host = "ab.internal" }
+        "#;
+
+        let rules = parse(contents, Format::Toml).unwrap();
+
+        let req = mk_req(http::Method::GET, "http://foo.com/a");
+        assert!(
+            rules[0].applies(&req),
+            "Composite triggers are mapped from the ruleset"
+        );
+
+        let req = mk_req(http::Method::POST, "http://foo.com/b");
+        assert!(
+            rules[0].applies(&req),
+            "Composite triggers are mapped from the ruleset"
+        );
+
+        let req = mk_req(http::Method::GET, "http://foo.com/b");
+        assert!(
+            !rules[0].applies(&req),
+            "Composite triggers are mapped from the ruleset"
+        );
+
+        let contents = r#"
+            [[rules]]
+            trigger.all = [{ not = { any_of = [{ path = "any" }, { method = { one_of = ["GET", "NO PE"] } }] } }]
+            action.proxy = { scheme = "http", host = "ab.internal" }
+        "#;
+
+        let (_, field) = rule_err(parse(contents, Format::Toml));
+        assert_eq!(
+            field, "trigger.all[0].not.any_of[1].method.one_of[1]",
+            "Errors point to the offending nested field"
+        );
+    }
+
+    #[test]
+    fn parse_path_templates() {
+        let contents = r#"
+            [[rules]]
+            trigger.path.template = "/users/{id}/orders/{order}"
+            action.proxy = { scheme = "http", host = "orders.internal", path.template = "/v2/orders/{order}?user={id}" }
+        "#;
+
+        let rules = parse(contents, Format::Toml).unwrap();
+
+        let mut req = mk_req(http::Method::GET, "http://foo.com/users/7/orders/42");
+        let params = rules[0].matches(&req).unwrap();
+        req.extensions_mut().insert(params);
+
+        let downstream = rules[0].transform_req(req).unwrap();
+        assert_eq!(
+            downstream.uri().to_string(),
+            "http://orders.internal/v2/orders/42?user=7&",
+            "Path templates are mapped from the ruleset"
+        );
+    }
+
+    #[test]
+    fn parse_path_rewrites() {
+        let contents = r#"
+            [[rules]]
+            trigger.path.regex = "^/api/svc(/|$)"
+            action.proxy = { scheme = "http", host = "svc.internal", path.strip_prefix = "/api/svc" }
+
+            [[rules]]
+            action.proxy.scheme = "http"
+            action.proxy.host = "legacy.internal"
+            action.proxy.path.regex_replace = { pattern = "^/old/(.*)$", replacement = "/new/$1" }
+        "#;
+
+        let rules = parse(contents, Format::Toml).unwrap();
+
+        let req = mk_req(http::Method::GET, "http://foo.com/api/svc/rest");
+        let downstream = rules[0].transform_req(req).unwrap();
+        assert_eq!(
+            downstream.uri().to_string(),
+            "http://svc.internal/rest",
+            "Prefix stripping is mapped from the ruleset"
+        );
+
+        let req = mk_req(http::Method::GET, "http://foo.com/old/x/y");
+        let downstream = rules[1].transform_req(req).unwrap();
+        assert_eq!(
+            downstream.uri().to_string(),
+            "http://legacy.internal/new/x/y",
+            "Regex path rewrites are mapped from the ruleset"
+        );
+
+        let contents = r#"
+            [[rules]]
+            action.proxy.scheme = "http"
+            action.proxy.host = "legacy.internal"
+            action.proxy.path.regex_replace = { pattern = "(", replacement = "/" }
+        "#;
+
+        let (_, field) = rule_err(parse(contents, Format::Toml));
+        assert_eq!(
+            field, "action.proxy.path.regex_replace.pattern",
+            "Errors point to the offending field"
+        );
+    }
+
+    #[test]
+    fn parse_upstreams() {
+        let contents = r#"
+            rules:
+              - action:
+                  proxy:
+                    upstreams:
+                      - { scheme: http, host: a.internal, weight: 2 }
+                      - { scheme: http, host: b.internal, port: 8080 }
+                    balance:
+                      consistent_hash:
+                        header: X-User
+                    path:
+                      prepend: /v1
+        "#;
+
+        let rules = parse(contents, Format::Yaml).unwrap();
+
+        let mk_user_req = |user: &str| {
+            http::Request::builder()
+                .uri("http://foo.com/x")
+                .header("X-User", user)
+                .body(())
+                .unwrap()
+        };
+
+        let first = rules[0].transform_req(mk_user_req("alice")).unwrap();
+        let second = rules[0].transform_req(mk_user_req("alice")).unwrap();
+        assert_eq!(
+            first.uri(),
+            second.uri(),
+            "Balancing strategies are mapped from the ruleset"
+        );
+        assert_eq!(
+            first.uri().path(),
+            "/v1/x",
+            "Upstream groups use the proxy's path rewriting"
+        );
+
+        let contents = r#"
+            [[rules]]
+            action.proxy.upstreams = [{ scheme = "http", host = "a.internal" }, { scheme = "http", host = "b.internal", weight = 0 }]
+        "#;
+
+        let (_, field) = rule_err(parse(contents, Format::Toml));
+        assert_eq!(
+            field, "action.proxy.upstreams[1].weight",
+            "Errors point to the offending field"
+        );
+
+        let contents = r#"
+            [[rules]]
+            action.proxy.upstreams = [{ scheme = "http", host = "a.internal", weight = 1001 }]
+        "#;
+
+        let (_, field) = rule_err(parse(contents, Format::Toml));
+        assert_eq!(
+            field, "action.proxy.upstreams[0].weight",
+            "Weights are bounded"
+        );
+
+        let contents = r#"
+            [[rules]]
+            action.proxy.upstreams = [{ scheme = "http", host = "a.internal" }]
+            action.proxy.balance.consistent_hash.header = "Not A Header"
+        "#;
+
+        let (_, field) = rule_err(parse(contents, Format::Toml));
+        assert_eq!(
+            field, "action.proxy.balance.consistent_hash.header",
+            "Errors point to the offending field"
+        );
+    }
+
+    #[test]
+    fn parse_health_checks() {
+        let contents = r#"
+            [[rules]]
+            action.proxy.upstreams = [{ scheme = "http", host = "a.internal" }]
+            action.proxy.health_check = { path = "/healthz", expected_status = 204, interval_secs = 5, fall = 1 }
+        "#;
+
+        let rules = parse(contents, Format::Toml).unwrap();
+
+        match rules[0].action() {
+            Action::Proxy(proxy) => {
+                let check = proxy.health_check().unwrap();
+                assert_eq!(check.path, "/healthz");
+                assert_eq!(check.expected_status, Some(StatusCode::NO_CONTENT));
+                assert_eq!(check.interval, Duration::from_secs(5));
+                assert_eq!(check.fall, 1);
+                assert_eq!(
+                    check.rise,
+                    HealthCheck::default().rise,
+                    "Omitted health check settings take defaults"
+                );
+            }
+            _ => panic!("expected a proxy action"),
+        }
+
+        let contents = r#"
+            [[rules]]
+            action.proxy.upstreams = [{ scheme = "http", host = "a.internal" }]
+            action.proxy.health_check = { rise = 0 }
+        "#;
+
+        let (_, field) = rule_err(parse(contents, Format::Toml));
+        assert_eq!(
+            field, "action.proxy.health_check.rise",
+            "Errors point to the offending field"
+        );
+    }
+
+    #[test]
+    fn parse_circuit_breakers() {
+        let contents = r#"
+            [[rules]]
+            action.proxy.upstreams = [{ scheme = "http", host = "a.internal" }]
+            action.proxy.circuit_breaker = { failure_threshold = 2 }
+        "#;
+
+        let rules = parse(contents, Format::Toml).unwrap();
+
+        match rules[0].action() {
+            Action::Proxy(proxy) => {
+                let upstream = &proxy.upstreams()[0];
+                upstream.record(false);
+                upstream.record(false);
+                assert_eq!(
+                    upstream.circuit_state(),
+                    Some(CircuitState::Open),
+                    "Circuit breakers are mapped from the ruleset"
+                );
+            }
+            _ => panic!("expected a proxy action"),
+        }
+
+        let contents = r#"
+            [[rules]]
+            action.proxy.upstreams = [{ scheme = "http", host = "a.internal" }]
+            action.proxy.circuit_breaker = { half_open_trials = 0 }
+        "#;
+
+        let (_, field) = rule_err(parse(contents, Format::Toml));
+        assert_eq!(
+            field, "action.proxy.circuit_breaker.half_open_trials",
+            "Errors point to the offending field"
+        );
+    }
+
+    #[test]
+    fn parse_retry_policies() {
+        let contents = r#"
+            [[rules]]
+            action.proxy.upstreams = [{ scheme = "http", host = "a.internal" }]
+            action.proxy.retry.max_attempts = 2
+            action.proxy.retry.retry_on = ["connect_error", { status = 503 }]
+            action.proxy.retry.methods.one_of = ["GET", "POST"]
+            action.proxy.retry.budget = { min_per_sec = 1 }
+        "#;
+
+        let rules = parse(contents, Format::Toml).unwrap();
+
+        match rules[0].action() {
+            Action::Proxy(proxy) => {
+                let retry = proxy.retry_policy().expect("retry policy");
+                assert_eq!(retry.max_attempts, 2, "Retry attempts are mapped");
+                assert_eq!(
+                    retry.retry_on,
+                    vec![
+                        RetryOn::ConnectError,
+                        RetryOn::Status(StatusCode::SERVICE_UNAVAILABLE)
+                    ],
+                    "Retried failures are mapped"
+                );
+                assert!(
+                    retry.allows(&mk_req(Method::POST, "/")),
+                    "Retried methods are mapped"
+                );
+                assert!(
+                    !retry.allows(&mk_req(Method::PUT, "/")),
+                    "Retried methods are mapped"
+                );
+            }
+            _ => panic!("expected a proxy action"),
+        }
+
+        let contents = r#"
+            [[rules]]
+            action.proxy.upstreams = [{ scheme = "http", host = "a.internal" }]
+            action.proxy.retry.retry_on = ["timeout", { status = 1000 }]
+        "#;
+
+        let (_, field) = rule_err(parse(contents, Format::Toml));
+        assert_eq!(
+            field, "action.proxy.retry.retry_on[1].status",
+            "Errors point to the offending field"
+        );
+    }
+
+    #[test]
+    fn parse_timeouts() {
+        let contents = r#"
+            [[rules]]
+            action.proxy.upstreams = [{ scheme = "http", host = "a.internal" }]
+            action.proxy.timeouts = { connect_ms = 500, total_ms = 30000 }
+        "#;
+
+        let rules = parse(contents, Format::Toml).unwrap();
+
+        match rules[0].action() {
+            Action::Proxy(proxy) => assert_eq!(
+                *proxy.timeouts(),
+                Timeouts {
+                    connect: Some(Duration::from_millis(500)),
+                    total: Some(Duration::from_secs(30)),
+                    ..Timeouts::default()
+                },
+                "Timeouts are mapped from the ruleset"
+            ),
+            _ => panic!("expected a proxy action"),
+        }
+
+        let contents = r#"
+            [[rules]]
+            action.proxy.upstreams = [{ scheme = "http", host = "a.internal" }]
+            action.proxy.timeouts = { idle_body_ms = 0 }
+        "#;
+
+        let rules = parse(contents, Format::Toml).unwrap();
+
+        match rules[0].action() {
+            Action::Proxy(proxy) => assert_eq!(
+                proxy.timeouts().or(&Timeouts {
+                    idle_body: Some(Duration::from_secs(60)),
+                    ..Timeouts::default()
+                }),
+                Timeouts::default(),
+                "0 disables a timeout, overriding the global default"
+            ),
+            _ => panic!("expected a proxy action"),
+        }
+    }
+
+    #[tokio::test]
+    async fn parse_responses() {
+        let dir = std::env::temp_dir().join(format!("warden-respond-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let page = dir.join("maintenance.html");
+        std::fs::write(&page, "<h1>Back soon</h1>").unwrap();
+
+        let contents = format!(
+            r#"
+            [[rules]]
+            trigger.path.exactly = "/robots.txt"
+            action.respond.body = "User-agent: *"
+
+            [[rules]]
+            action.respond.status = 503
+            action.respond.headers = {{ retry-after = "120" }}
+            action.respond.body_file = {:?}
+        "#,
+            page
+        );
+
+        let rules = parse(&contents, Format::Toml).unwrap();
+
+        let req = mk_req(Method::GET, "/robots.txt");
+        assert!(rules[0].applies(&req), "Responses work with triggers");
+        match rules[0].action() {
+            Action::Respond(respond) => {
+                let res = respond.response();
+                assert_eq!(res.status(), StatusCode::OK, "Status defaults to 200");
+                assert_eq!(res.headers()["content-type"], "text/plain; charset=utf-8");
+
+                let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+                assert_eq!(body, "User-agent: *", "Inline bodies are mapped");
+            }
+            _ => panic!("expected a respond action"),
+        }
+
+        match rules[1].action() {
+            Action::Respond(respond) => {
+                let res = respond.response();
+                assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+                assert_eq!(res.headers()["retry-after"], "120", "Headers are mapped");
+                assert_eq!(
+                    res.headers()["content-type"],
+                    "text/html",
+                    "Content types are guessed from file names"
+                );
+
+                let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+                assert_eq!(body, "<h1>Back soon</h1>", "Bodies are read from files");
+            }
+            _ => panic!("expected a respond action"),
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let contents = r#"
+            [[rules]]
+            action.respond.body_file = "/nonexistent/warden/page.html"
+        "#;
+
+        let (_, field) = rule_err(parse(contents, Format::Toml));
+        assert_eq!(
+            field, "action.respond.body_file",
+            "Errors point to the offending field"
+        );
+
+        let contents = r#"
+            [[rules]]
+            action.respond.headers = { "bad header" = "x" }
+        "#;
+
+        let (_, field) = rule_err(parse(contents, Format::Toml));
+        assert_eq!(
+            field, "action.respond.headers.bad header",
+            "Errors point to the offending field"
+        );
+    }
+
+    #[test]
+    fn parse_redirects() {
+        let contents = r#"
+            [[rules]]
+            trigger.path.template = "/old/{rest}"
+            action.redirect = { status = 308, path.template = "/new/{rest}" }
+
+            [[rules]]
+            action.redirect = { scheme = "https", host = "www.example.com" }
+        "#;
+
+        let rules = parse(contents, Format::Toml).unwrap();
+
+        let mut req = mk_req(Method::GET, "/old/page?x=1");
+        let params = rules[0].matches(&req).unwrap();
+        req.extensions_mut().insert(params);
+        match rules[0].action() {
+            Action::Redirect(redirect) => {
+                let res = redirect.response(&req).unwrap();
+                assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+                assert_eq!(
+                    res.headers()["location"],
+                    "/new/page?x=1",
+                    "Redirect paths are mapped"
+                );
+            }
+            _ => panic!("expected a redirect action"),
+        }
+
+        match rules[1].action() {
+            Action::Redirect(redirect) => {
+                let res = redirect.response(&mk_req(Method::GET, "/a")).unwrap();
+                assert_eq!(res.status(), StatusCode::FOUND, "Status defaults to 302");
+                assert_eq!(res.headers()["location"], "https://www.example.com/a");
+            }
+            _ => panic!("expected a redirect action"),
+        }
+
+        let contents = r#"
+            [[rules]]
+            action.redirect.status = 200
+        "#;
+
+        let (_, field) = rule_err(parse(contents, Format::Toml));
+        assert_eq!(
+            field, "action.redirect.status",
+            "Errors point to the offending field"
+        );
+    }
+
+    #[tokio::test]
+    async fn parse_serve_dirs() {
+        let dir = std::env::temp_dir().join(format!("warden-serve-dir-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("index.html"), "<h1>app</h1>").unwrap();
+
+        let contents = format!(
+            r#"
+            [[rules]]
+            trigger.path.template = "/app/{{rest}}"
+            action.serve_dir = {{ dir = {:?}, path.strip_prefix = "/app", fallback = {:?} }}
+        "#,
+            dir,
+            dir.join("index.html")
+        );
+
+        let rules = parse(&contents, Format::Toml).unwrap();
+
+        match rules[0].action() {
+            Action::ServeDir(serve_dir) => {
+                let res = serve_dir
+                    .serve(&mk_req(Method::GET, "/app/x"))
+                    .await
+                    .unwrap();
+                let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+                assert_eq!(body, "<h1>app</h1>", "Directories are mapped");
+            }
+            _ => panic!("expected a serve_dir action"),
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let contents = r#"
+            [[rules]]
+            action.serve_dir.dir = "/nonexistent/warden"
+        "#;
+
+        let (_, field) = rule_err(parse(contents, Format::Toml));
+        assert_eq!(
+            field, "action.serve_dir.dir",
+            "Errors point to the offending field"
+        );
+    }
+
+    #[test]
+    fn parse_header_updates() {
+        let contents = r#"
+            [[rules]]
+            trigger.path.template = "/users/{id}"
+            action.proxy.upstreams = [{ scheme = "http", host = "a.internal" }]
+            action.proxy.headers = [
+                { remove = "cookie" },
+                { remove_matching = "^x-debug-" },
+                { rename = { from = "x-token", to = "authorization" } },
+                { set = { name = "x-user-id", value = "{id}" } },
+                { set = { name = "x-user", value = '{{"id": {id}, "ip": "{client_ip}"}}' } },
+                { append = { name = "via", value = "warden" } },
+            ]
+        "#;
+
+        let rules = parse(contents, Format::Toml).unwrap();
+
+        let mut req = http::Request::builder()
+            .uri("/users/7")
+            .header("cookie", "a=b")
+            .header("x-debug-trace", "1")
+            .header("x-token", "t")
+            .body(())
+            .unwrap();
+        let params = rules[0].matches(&req).unwrap();
+        req.extensions_mut().insert(params);
+
+        let downstream = rules[0].transform_req(req).unwrap();
+        let headers = downstream.headers();
+        assert!(!headers.contains_key("cookie"));
+        assert!(!headers.contains_key("x-debug-trace"));
+        assert_eq!(headers["authorization"], "t");
+        assert_eq!(headers["x-user-id"], "7", "Header updates are mapped");
+        assert_eq!(
+            headers["x-user"], r#"{"id": 7, "ip": ""}"#,
+            "Doubled braces in header templates are literal"
+        );
+        assert_eq!(headers["via"], "warden");
+
+        let contents = r#"
+            [[rules]]
+            trigger.path.template = "/users/{id}"
+            action.proxy.upstreams = [{ scheme = "http", host = "a.internal" }]
+            action.proxy.headers = [{ set = { name = "x-user-id", value = "{user}" } }]
+        "#;
+
+        match parse(contents, Format::Toml) {
+            Err(Error::Rule { field, message, .. }) => assert_eq!(
+                (field.as_str(), message.as_str()),
+                (
+                    "action.proxy.headers[0].set.value",
+                    "unknown placeholder {user}"
+                ),
+                "Header templates may only refer to built-in values and captured params"
+            ),
+            _ => panic!("expected a rule error"),
+        }
+
+        let contents = r#"
+            [[rules]]
+            action.redirect.path.template = "/users/{id}"
+        "#;
+
+        let (_, field) = rule_err(parse(contents, Format::Toml));
+        assert_eq!(
+            field, "action.redirect.path.template",
+            "Path templates may only refer to captured params"
+        );
+
+        let contents = r#"
+            [[rules]]
+            action.proxy.upstreams = [{ scheme = "http", host = "a.internal" }]
+            action.proxy.headers = [{ remove = "x-a" }, { rename = { from = "x-b", to = "bad name" } }]
+        "#;
+
+        let (_, field) = rule_err(parse(contents, Format::Toml));
+        assert_eq!(
+            field, "action.proxy.headers[1].rename.to",
+            "Errors point to the offending field"
+        );
+    }
+
+    #[test]
+    fn parse_response_updates() {
+        let contents = r#"
+            [[rules]]
+            action.proxy.upstreams = [{ scheme = "http", host = "a.internal" }]
+            action.proxy.response.status = 200
+            action.proxy.response.locations = [
+                "upstream",
+                { prefix = { from = "/v2/", to = "/api/" } },
+            ]
+            action.proxy.response.cookies = [{ domain = { from = "a.internal", to = "{host}" } }]
+            action.proxy.response.headers = [{ remove = "server" }]
+        "#;
+
+        let rules = parse(contents, Format::Toml).unwrap();
+
+        match rules[0].action() {
+            Action::Proxy(proxy) => {
+                let mut res = http::Response::builder()
+                    .status(302)
+                    .header("location", "http://a.internal/v2/home")
+                    .header("set-cookie", "id=1; Domain=a.internal")
+                    .header("server", "app")
+                    .body(())
+                    .unwrap();
+                let req = http::Request::builder()
+                    .uri("/home")
+                    .header("host", "example.com")
+                    .body(())
+                    .unwrap();
+
+                proxy
+                    .response_update()
+                    .unwrap()
+                    .apply(&mut res, &req, &proxy.upstreams()[0]);
+
+                assert_eq!(res.status(), StatusCode::OK, "Status overrides are mapped");
+                assert_eq!(
+                    res.headers()["location"],
+                    "/v2/home",
+                    "Location rewrites are mapped, and the first that applies is used"
+                );
+                assert_eq!(res.headers()["set-cookie"], "id=1; Domain=example.com");
+                assert!(!res.headers().contains_key("server"));
+            }
+            _ => panic!("expected a proxy action"),
+        }
+
+        let contents = r#"
+            [[rules]]
+            action.proxy.upstreams = [{ scheme = "http", host = "a.internal" }]
+            action.proxy.response.headers = [{ remove_matching = "(" }]
+        "#;
+
+        let (_, field) = rule_err(parse(contents, Format::Toml));
+        assert_eq!(
+            field, "action.proxy.response.headers[0].remove_matching",
+            "Errors point to the offending field"
+        );
+    }
+
+    #[test]
+    fn parse_forwarding() {
+        let contents = r#"
+            [[rules]]
+            action.proxy.upstreams = [{ scheme = "http", host = "a.internal" }]
+            action.proxy.preserve_host = true
+            action.proxy.trusted_proxies = ["10.0.0.0/8", "fd00::/8"]
+        "#;
+
+        let rules = parse(contents, Format::Toml).unwrap();
+
+        let mut req = http::Request::builder()
+            .uri("/")
+            .header("host", "example.com")
+            .header("x-forwarded-for", "203.0.113.9")
+            .body(())
+            .unwrap();
+        req.extensions_mut()
+            .insert(crate::conn::ClientAddr(std::net::SocketAddr::from((
+                [10, 0, 0, 5],
+                4000,
+            ))));
+
+        let downstream = rules[0].transform_req(req).unwrap();
+        let headers = downstream.headers();
+        assert_eq!(headers["host"], "example.com", "Preserving Host is mapped");
+        assert_eq!(
+            headers["x-forwarded-for"], "203.0.113.9, 10.0.0.5",
+            "Trusted proxies are mapped"
+        );
+
+        let contents = r#"
+            [[rules]]
+            action.proxy.upstreams = [{ scheme = "http", host = "a.internal" }]
+            action.proxy.trusted_proxies = ["10.0.0.0/8", "10.0.0.0/40"]
+        "#;
+
+        let (_, field) = rule_err(parse(contents, Format::Toml));
+        assert_eq!(
+            field, "action.proxy.trusted_proxies[1]",
+            "Errors point to the offending field"
+        );
+    }
+
+    #[test]
+    fn parse_upstream_tls() {
+        let dir = std::env::temp_dir().join(format!("warden-config-tls-{}", std::process::id()));
+        let ca = crate::tls::tests::self_signed(&dir, "ca", &["upstream.internal"]);
+        let client = crate::tls::tests::self_signed(&dir, "client", &["warden.internal"]);
+
+        let contents = format!(
+            r#"
+            [[rules]]
+            action.proxy.upstreams = [{{ scheme = "https", host = "10.0.0.1" }}]
+            action.proxy.tls.ca = {:?}
+            action.proxy.tls.cert = {:?}
+            action.proxy.tls.key = {:?}
+            action.proxy.tls.server_name = "upstream.internal"
+        "#,
+            ca.cert, client.cert, client.key
+        );
+
+        let rules = parse(&contents, Format::Toml).unwrap();
+        let expected = UpstreamTls::new()
+            .ca(crate::tls::read_certs(&ca.cert).unwrap().remove(0).0)
+            .identity(
+                std::fs::read(&client.cert).unwrap(),
+                crate::tls::read_pkcs8_key(&client.key).unwrap(),
+            )
+            .server_name(String::from("upstream.internal"));
+        match rules[0].action() {
+            Action::Proxy(proxy) => {
+                assert_eq!(
+                    proxy.upstream_tls(),
+                    Some(&expected),
+                    "TLS settings are mapped"
+                )
+            }
+            _ => panic!("expected a proxy action"),
+        }
+
+        let contents = format!(
+            r#"
+            [[rules]]
+            action.proxy.upstreams = [{{ scheme = "https", host = "10.0.0.1" }}]
+            action.proxy.tls.key = {:?}
+        "#,
+            client.key
+        );
+        let (_, field) = rule_err(parse(&contents, Format::Toml));
+        assert_eq!(field, "action.proxy.tls", "A key needs a certificate");
+
+        let contents = r#"
+            [[rules]]
+            action.proxy.upstreams = [{ scheme = "https", host = "10.0.0.1" }]
+            action.proxy.tls.ca = "/nonexistent/warden/ca.pem"
+        "#;
+        let (_, field) = rule_err(parse(contents, Format::Toml));
+        assert_eq!(
+            field, "action.proxy.tls.ca",
+            "Errors point to the offending field"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_client_certs() {
+        let fingerprint = "AB:".repeat(31) + "AB";
+        let contents = format!(
+            r#"
+            [[rules]]
+            trigger.client_cert.fingerprint = ["{}"]
+            action.proxy.upstreams = [{{ scheme = "http", host = "a.internal" }}]
+            action.proxy.client_cert_headers = {{ subject = "x-client-subject" }}
+        "#,
+            fingerprint
+        );
+
+        let rules = parse(&contents, Format::Toml).unwrap();
+
+        let mut req = mk_req(Method::GET, "/");
+        req.extensions_mut().insert(crate::conn::TlsInfo {
+            client_cert: Some(crate::conn::ClientCert {
+                subject: String::from("CN=svc-a"),
+                sans: Vec::new(),
+                fingerprint: "ab".repeat(32),
+            }),
+            ..crate::conn::TlsInfo::default()
+        });
+        assert!(
+            rules[0].matches(&req).is_some(),
+            "Fingerprints are normalised"
+        );
+
+        let downstream = rules[0].transform_req(req).unwrap();
+        assert_eq!(
+            downstream.headers()["x-client-subject"],
+            "CN=svc-a",
+            "Identity headers are mapped"
+        );
+
+        let contents = r#"
+            [[rules]]
+            trigger.client_cert.fingerprint = ["ab:cd"]
+            action.proxy.upstreams = [{ scheme = "http", host = "a.internal" }]
+        "#;
+
+        let (_, field) = rule_err(parse(contents, Format::Toml));
+        assert_eq!(
+            field, "trigger.client_cert.fingerprint[0]",
+            "Errors point to the offending field"
+        );
+    }
+
+    #[test]
+    fn parse_listeners() {
+        let dir =
+            std::env::temp_dir().join(format!("warden-config-listeners-{}", std::process::id()));
+        let cert = crate::tls::tests::self_signed(&dir, "public", &["example.com"]);
+
+        let contents = format!(
+            r#"
+            [[rules]]
+            action.proxy = {{ scheme = "http", host = "app.internal" }}
+
+            [[listeners]]
+            addr = "0.0.0.0"
+            port = 80
+            protocols = "http1_only"
+            rules = [{{ action.redirect = {{ scheme = "https", status = 308 }} }}]
+
+            [[listeners]]
+            addr = "0.0.0.0"
+            port = 443
+            tls.certs = [{{ cert = {:?}, key = {:?} }}]
+            tls.min_version = "1.3"
+
+            [[listeners]]
+            port = 9000
+            protocols = "http2_only"
+            rules = []
+        "#,
+            cert.cert, cert.key
+        );
+
+        let config = super::parse(&contents, Format::Toml).unwrap();
+        assert_eq!(config.rules.len(), 1);
+        assert_eq!(config.listeners.len(), 3);
+
+        let http = &config.listeners[0];
+        assert_eq!(http.settings.addr, SocketAddr::from(([0, 0, 0, 0], 80)));
+        assert_eq!(http.settings.protocols, Protocols::Http1Only);
+        assert!(
+            http.settings.tls.is_none(),
+            "Listeners are plain by default"
+        );
+        assert!(
+            matches!(http.rules.as_deref(), Some([rule]) if matches!(rule.action(), Action::Redirect(_))),
+            "Listeners can have their own rules"
+        );
+
+        let https = &config.listeners[1];
+        let tls = https.settings.tls.as_ref().unwrap();
+        assert_eq!(tls.certs, vec![cert.clone()]);
+        assert_eq!(tls.min_version, TlsVersion::Tls13);
+        assert_eq!(
+            tls.alpn,
+            vec!["h2", "http/1.1"],
+            "ALPN follows the protocols"
+        );
+        assert!(
+            https.rules.is_none(),
+            "Listeners without rules share the top-level rules"
+        );
+
+        let admin = &config.listeners[2];
+        assert_eq!(
+            admin.settings.addr,
+            SocketAddr::from((Ipv6Addr::LOCALHOST, 9000)),
+            "Listeners are local by default"
+        );
+        assert!(matches!(admin.rules.as_deref(), Some([])));
+
+        let contents = r#"
+            [[listeners]]
+            port = 80
+            rules = [{ action.redirect = { status = 200 } }]
+        "#;
+        match super::parse(contents, Format::Toml) {
+            Err(Error::Listener { index, field, .. }) => {
+                assert_eq!(index, 0);
+                assert_eq!(
+                    field, "rules[0].action.redirect.status",
+                    "Errors in listener rules point to the offending field"
+                );
+            }
+            _ => panic!("expected a listener error"),
+        }
+
+        let contents = r#"
+            [[listeners]]
+            port = 443
+            tls.certs = [{ cert = "/nonexistent/warden/a.crt", key = "/nonexistent/warden/a.key" }]
+        "#;
+        assert!(
+            matches!(super::parse(contents, Format::Toml), Err(Error::Listener { field, .. }) if field == "tls.certs"),
+            "Unusable certificates are rejected"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rule_errors() {
+        let missing = r#"
+            [[rules]]
+            action.proxy = { scheme = "http", host = "a.internal" }
+
+            [[rules]]
+            action.proxy = { scheme = "http" }
+        "#;
+        let (index, field) = rule_err(parse(missing, Format::Toml));
+        assert_eq!(index, 1, "Errors point to the offending rule");
+        assert_eq!(field, "action.proxy", "Errors point to the offending field");
+
+        let bad_method = r#"
+            [[rules]]
+            trigger.method = { one_of = ["GET", "NOT A METHOD"] }
+            action.proxy = { scheme = "http", host = "a.internal" }
+        "#;
+        let (index, field) = rule_err(parse(bad_method, Format::Toml));
+        assert_eq!(index, 0, "Errors point to the offending rule");
+        assert_eq!(
+            field, "trigger.method.one_of[1]",
+            "Errors point to the offending field"
+        );
+
+        let bad_regex = r#"
+            [[rules]]
+            trigger.path = { regex = "(" }
+            action.proxy = { scheme = "http", host = "a.internal" }
+        "#;
+        let (_, field) = rule_err(parse(bad_regex, Format::Toml));
+        assert_eq!(
+            field, "trigger.path.regex",
+            "Errors point to the offending field"
+        );
+    }
+
+    #[test]
+    fn format_from_path() {
+        assert_eq!(Format::from_path(Path::new("a.toml")), Some(Format::Toml));
+        assert_eq!(Format::from_path(Path::new("a.yml")), Some(Format::Yaml));
+        assert_eq!(Format::from_path(Path::new("a.yaml")), Some(Format::Yaml));
+        assert_eq!(Format::from_path(Path::new("a.json")), Some(Format::Json));
+        assert_eq!(Format::from_path(Path::new("a.txt")), None);
+    }
+}
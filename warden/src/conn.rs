@@ -0,0 +1,42 @@
+use http::Request;
+use std::net::SocketAddr;
+
+/// Address of the client connected to warden, added to the extensions of every request received
+/// on the connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientAddr(pub SocketAddr);
+
+/// Details of the TLS session a request was received on, added to the extensions of every request
+/// received on a TLS listener.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// Server name the client sent with SNI.
+    pub server_name: Option<String>,
+    /// Protocol negotiated with ALPN.
+    pub alpn: Option<String>,
+    /// The client certificate, if the client presented one and it was verified.
+    pub client_cert: Option<ClientCert>,
+}
+
+/// Identity from a verified client certificate.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientCert {
+    /// Subject distinguished name, such as `CN=svc-a, O=Example`.
+    pub subject: String,
+    /// DNS, email and URI subject alternative names.
+    pub sans: Vec<String>,
+    /// SHA-256 of the DER certificate, as lowercase hex.
+    pub fingerprint: String,
+}
+
+/// Header carrying the ID assigned to each request, for correlating logs and error responses.
+pub const REQUEST_ID: &str = "x-request-id";
+
+/// Scheme the client used to reach warden.
+pub fn client_scheme<T>(req: &Request<T>) -> &str {
+    if req.extensions().get::<TlsInfo>().is_some() {
+        "https"
+    } else {
+        req.uri().scheme_str().unwrap_or("http")
+    }
+}
@@ -0,0 +1,256 @@
+use http::header::{HeaderValue, CONTENT_TYPE};
+use http::{Response, StatusCode};
+use hyper::Body;
+use std::error::Error;
+use std::path::Path;
+use std::{fmt, fs, io};
+
+/// Why a request could not be proxied.
+#[derive(Debug)]
+pub enum ProxyError {
+    /// The downstream request URI could not be built from the rule.
+    InvalidUri,
+    /// No upstream is available, e.g. all are unhealthy or their circuits are open.
+    NoUpstream,
+    /// The client's request body could not be read.
+    RequestBody(hyper::Error),
+    Connect(hyper::Error),
+    /// The TLS handshake with the upstream failed.
+    Tls(hyper::Error),
+    /// The proxy's upstream TLS settings could not be used.
+    TlsConfig(native_tls::Error),
+    /// Connecting, or waiting for the response, took too long.
+    Timeout,
+    /// The upstream failed after the connection was made.
+    Upstream(hyper::Error),
+}
+
+impl ProxyError {
+    /// Classify an error from the client.
+    pub fn from_client(err: hyper::Error) -> Self {
+        if caused_by(&err, |e| {
+            e.downcast_ref::<io::Error>()
+                .is_some_and(|io| io.kind() == io::ErrorKind::TimedOut)
+        }) {
+            Self::Timeout
+        } else if caused_by(&err, |e| e.is::<native_tls::Error>()) {
+            Self::Tls(err)
+        } else if err.is_connect() {
+            Self::Connect(err)
+        } else {
+            Self::Upstream(err)
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::InvalidUri => StatusCode::BAD_GATEWAY,
+            Self::NoUpstream => StatusCode::SERVICE_UNAVAILABLE,
+            Self::RequestBody(_) => StatusCode::BAD_REQUEST,
+            Self::Connect(_) => StatusCode::BAD_GATEWAY,
+            Self::Tls(_) => StatusCode::BAD_GATEWAY,
+            Self::TlsConfig(_) => StatusCode::BAD_GATEWAY,
+            Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            Self::Upstream(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidUri => write!(f, "could not build upstream request URI"),
+            Self::NoUpstream => write!(f, "no upstream available"),
+            Self::RequestBody(_) => write!(f, "could not read request body"),
+            Self::Connect(_) => write!(f, "could not connect to upstream"),
+            Self::Tls(_) => write!(f, "TLS handshake with upstream failed"),
+            Self::TlsConfig(_) => write!(f, "invalid upstream TLS settings"),
+            Self::Timeout => write!(f, "upstream timed out"),
+            Self::Upstream(_) => write!(f, "upstream request failed"),
+        }
+    }
+}
+
+impl Error for ProxyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::RequestBody(e) | Self::Connect(e) | Self::Tls(e) | Self::Upstream(e) => Some(e),
+            Self::TlsConfig(e) => Some(e),
+            Self::InvalidUri | Self::NoUpstream | Self::Timeout => None,
+        }
+    }
+}
+
+fn caused_by(err: &hyper::Error, f: impl Fn(&(dyn Error + 'static)) -> bool) -> bool {
+    let mut source = err.source();
+
+    while let Some(err) = source {
+        if f(err) {
+            return true;
+        }
+        source = err.source();
+    }
+
+    false
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Text,
+    Json,
+    Html,
+}
+
+/// Template for the bodies of error responses generated by warden. `{status}`, `{reason}`,
+/// `{message}` and `{request_id}` are replaced, escaped as appropriate for the format.
+#[derive(Clone, Debug)]
+pub struct ErrorPage {
+    template: String,
+    format: Format,
+}
+
+impl Default for ErrorPage {
+    fn default() -> Self {
+        Self {
+            template: String::from("{reason}"),
+            format: Format::Text,
+        }
+    }
+}
+
+impl ErrorPage {
+    /// Load a template, in JSON or HTML if the file has a `.json` or `.html` extension, or plain
+    /// text otherwise.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let format = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Format::Json,
+            Some("html") | Some("htm") => Format::Html,
+            _ => Format::Text,
+        };
+
+        Ok(Self {
+            template: fs::read_to_string(path)?,
+            format,
+        })
+    }
+
+    pub fn render(
+        &self,
+        status: StatusCode,
+        message: &str,
+        request_id: Option<&HeaderValue>,
+    ) -> Response<Body> {
+        let request_id = request_id.and_then(|id| id.to_str().ok()).unwrap_or("");
+
+        let body = self
+            .template
+            .replace("{status}", status.as_str())
+            .replace(
+                "{reason}",
+                &self.escape(status.canonical_reason().unwrap_or("")),
+            )
+            .replace("{message}", &self.escape(message))
+            .replace("{request_id}", &self.escape(request_id));
+
+        let content_type = match self.format {
+            Format::Text => "text/plain; charset=utf-8",
+            Format::Json => "application/json",
+            Format::Html => "text/html; charset=utf-8",
+        };
+
+        Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .expect("can construct error response")
+    }
+
+    fn escape(&self, value: &str) -> String {
+        match self.format {
+            Format::Text => value.to_string(),
+            Format::Json => {
+                let quoted = serde_json::to_string(value).expect("strings serialize");
+                quoted[1..quoted.len() - 1].to_string()
+            }
+            Format::Html => value
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;")
+                .replace('\'', "&#39;"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn body(res: Response<Body>) -> String {
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn status() {
+        assert_eq!(ProxyError::InvalidUri.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            ProxyError::NoUpstream.status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(ProxyError::Timeout.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn default_page() {
+        let res =
+            ErrorPage::default().render(StatusCode::GATEWAY_TIMEOUT, "upstream timed out", None);
+
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(
+            body(res).await,
+            "Gateway Timeout",
+            "Default pages give the reason"
+        );
+    }
+
+    #[tokio::test]
+    async fn json_page() {
+        let page = ErrorPage {
+            template: String::from(
+                r#"{"status": {status}, "message": "{message}", "id": "{request_id}"}"#,
+            ),
+            format: Format::Json,
+        };
+
+        let id = HeaderValue::from_static("abc");
+        let res = page.render(StatusCode::BAD_GATEWAY, "say \"hi\"", Some(&id));
+        assert_eq!(
+            res.headers()[CONTENT_TYPE],
+            "application/json",
+            "JSON pages have a JSON content type"
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&body(res).await).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"status": 502, "message": "say \"hi\"", "id": "abc"}),
+            "Values are escaped as JSON strings"
+        );
+    }
+
+    #[tokio::test]
+    async fn html_page() {
+        let page = ErrorPage {
+            template: String::from("<p>{message}</p>"),
+            format: Format::Html,
+        };
+
+        let res = page.render(StatusCode::BAD_GATEWAY, "<script>", None);
+        assert_eq!(
+            body(res).await,
+            "<p>&lt;script&gt;</p>",
+            "Values are escaped as HTML"
+        );
+    }
+}
@@ -0,0 +1,461 @@
+use crate::action::forwarding::strip_hop_by_hop;
+use crate::action::proxy::Proxy;
+use crate::client::Clients;
+use crate::error::ProxyError;
+use crate::retry::Failure;
+use crate::timeout::TimeoutBody;
+use futures_util::stream::{self, StreamExt};
+use http::header::CONTENT_LENGTH;
+use http::{Request, Response};
+use hyper::body::{Bytes, HttpBody};
+use hyper::Body;
+use tokio::time::Instant;
+
+/// Forward a request to one of the proxy's upstreams, retrying according to its retry policy.
+pub async fn forward(
+    proxy: &Proxy,
+    req: Request<Body>,
+    clients: &Clients,
+) -> Result<Response<Body>, ProxyError> {
+    let timeouts = proxy.timeouts().or(clients.default_timeouts());
+    let client = clients
+        .client(timeouts.connect, proxy.upstream_tls())
+        .map_err(ProxyError::TlsConfig)?;
+    let deadline = timeouts.total.map(|total| Instant::now() + total);
+
+    if let Some(policy) = proxy.retry_policy() {
+        policy.budget.deposit();
+    }
+
+    let policy = proxy.retry_policy().filter(|p| p.allows(&req));
+
+    let (parts, body) = req.into_parts();
+    let head = Request::from_parts(parts, ());
+
+    let mut body = match policy {
+        Some(p) => RequestBody::buffer(body, p.max_body_bytes, &head).await?,
+        None => RequestBody::Streaming(Some(body)),
+    };
+
+    let mut attempt = 1;
+
+    loop {
+        let upstream = proxy.select(&head).ok_or(ProxyError::NoUpstream)?;
+
+        let downstream_req = match (proxy.transform_req_to(&head, &upstream), body.take()) {
+            (Some(builder), Some(b)) => builder.body(b).map_err(|_| ProxyError::InvalidUri)?,
+            _ => return Err(ProxyError::InvalidUri),
+        };
+
+        let first_byte = timeouts.first_byte.map(|t| Instant::now() + t);
+        let res = match earliest(first_byte, deadline) {
+            Some(at) => tokio::time::timeout_at(at, client.request(downstream_req))
+                .await
+                .map_err(|_| ProxyError::Timeout)
+                .and_then(|r| r.map_err(ProxyError::from_client)),
+            None => client
+                .request(downstream_req)
+                .await
+                .map_err(ProxyError::from_client),
+        };
+
+        let failure = match &res {
+            Ok(r) if r.status().is_server_error() => Some(Failure::Status(r.status())),
+            Ok(_) => None,
+            Err(ProxyError::Timeout) => Some(Failure::Timeout),
+            Err(ProxyError::Connect(_)) | Err(ProxyError::Tls(_)) => Some(Failure::Connect),
+            Err(_) => None,
+        };
+
+        upstream.record(res.is_ok() && failure.is_none());
+
+        if let (Some(p), Some(f)) = (policy, failure) {
+            let backoff = p.backoff(attempt);
+            let in_time = match deadline {
+                Some(d) => Instant::now() + backoff < d,
+                None => true,
+            };
+
+            if in_time && body.replayable() && p.retries(attempt, f) {
+                tracing::debug!(
+                    "retrying request to {} after {:?} (attempt {})",
+                    upstream.authority(),
+                    f,
+                    attempt
+                );
+
+                drop(upstream);
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+                continue;
+            }
+        }
+
+        return res.map(|mut res| {
+            strip_hop_by_hop(res.headers_mut());
+
+            if let Some(update) = proxy.response_update() {
+                update.apply(&mut res, &head, &upstream);
+            }
+
+            res.map(|b| TimeoutBody::wrap(b, timeouts.idle_body, deadline))
+        });
+    }
+}
+
+fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+enum RequestBody {
+    Buffered(Bytes),
+    Streaming(Option<Body>),
+}
+
+impl RequestBody {
+    /// Buffer a request body so it can be replayed, unless it is larger than `limit`, in which
+    /// case it is streamed once.
+    async fn buffer<T>(
+        mut body: Body,
+        limit: usize,
+        head: &Request<T>,
+    ) -> Result<Self, ProxyError> {
+        let content_length = head
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+
+        if content_length.is_some_and(|l| l > limit) {
+            return Ok(Self::Streaming(Some(body)));
+        }
+
+        let mut buf = Vec::new();
+
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(ProxyError::RequestBody)?;
+
+            if buf.len() + chunk.len() > limit {
+                // Too large to replay: send what was read, followed by the rest of the stream.
+                let read = stream::iter(vec![Ok(Bytes::from(buf)), Ok(chunk)]);
+                return Ok(Self::Streaming(Some(Body::wrap_stream(read.chain(body)))));
+            }
+
+            buf.extend_from_slice(&chunk);
+        }
+
+        Ok(Self::Buffered(Bytes::from(buf)))
+    }
+
+    fn take(&mut self) -> Option<Body> {
+        match self {
+            Self::Buffered(bytes) => Some(Body::from(bytes.clone())),
+            Self::Streaming(body) => body.take(),
+        }
+    }
+
+    fn replayable(&self) -> bool {
+        matches!(self, Self::Buffered(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::upstream::Upstream;
+    use crate::client::{ClientConfig, Clients, UpstreamTls};
+    use crate::listener::Listener;
+    use crate::retry::{RetryOn, RetryPolicy};
+    use crate::timeout::Timeouts;
+    use crate::tls::tests::self_signed;
+    use crate::tls::{read_certs, CertPaths, TlsSettings, TlsVersion};
+    use http::{Method, StatusCode};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::Server;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    /// Start a server that echoes request bodies with the given status.
+    fn echo_server(status: StatusCode) -> SocketAddr {
+        let make_service = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| async move {
+                let body = hyper::body::to_bytes(req.into_body()).await?;
+                let mut res = Response::new(Body::from(body));
+                *res.status_mut() = status;
+                Ok::<_, hyper::Error>(res)
+            }))
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        addr
+    }
+
+    /// Start a server that waits before responding.
+    fn slow_server(delay: Duration) -> SocketAddr {
+        let make_service = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |_: Request<Body>| async move {
+                tokio::time::sleep(delay).await;
+                Ok::<_, Infallible>(Response::new(Body::from("slow")))
+            }))
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        addr
+    }
+
+    fn upstream(addr: SocketAddr) -> Upstream {
+        Upstream::new(
+            String::from("http"),
+            addr.ip().to_string(),
+            Some(addr.port()),
+        )
+    }
+
+    fn mk_proxy(addrs: &[SocketAddr], retry: Option<RetryPolicy>) -> Proxy {
+        let builder = addrs
+            .iter()
+            .fold(Proxy::builder(), |b, addr| b.upstream(upstream(*addr)));
+
+        match retry {
+            Some(r) => builder.retry(r).build().unwrap(),
+            None => builder.build().unwrap(),
+        }
+    }
+
+    fn mk_req(method: Method, body: &'static str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri("http://warden.test/")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn send(proxy: &Proxy, req: Request<Body>) -> (StatusCode, Bytes) {
+        let clients = Clients::new(ClientConfig::default());
+        let res = match forward(proxy, req, &clients).await {
+            Ok(r) => r,
+            Err(_) => panic!("request not forwarded"),
+        };
+
+        let status = res.status();
+        (
+            status,
+            hyper::body::to_bytes(res.into_body()).await.unwrap(),
+        )
+    }
+
+    fn retry_503() -> RetryPolicy {
+        RetryPolicy {
+            retry_on: vec![RetryOn::Status(StatusCode::SERVICE_UNAVAILABLE)],
+            methods: crate::trigger::method::MethodTrigger::Any,
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn no_retry_policy() {
+        let failing = echo_server(StatusCode::SERVICE_UNAVAILABLE);
+        let ok = echo_server(StatusCode::OK);
+        let proxy = mk_proxy(&[failing, ok], None);
+
+        let (status, _) = send(&proxy, mk_req(Method::GET, "")).await;
+        assert_eq!(
+            status,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Requests are attempted once without a retry policy"
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_with_body() {
+        let failing = echo_server(StatusCode::SERVICE_UNAVAILABLE);
+        let ok = echo_server(StatusCode::OK);
+        let proxy = mk_proxy(&[failing, ok], Some(retry_503()));
+
+        let (status, body) = send(&proxy, mk_req(Method::POST, "payload")).await;
+        assert_eq!(status, StatusCode::OK, "Failed attempts are retried");
+        assert_eq!(
+            body,
+            Bytes::from("payload"),
+            "Buffered request bodies are replayed"
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_connect_errors() {
+        let closed = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let ok = echo_server(StatusCode::OK);
+
+        let policy = RetryPolicy {
+            backoff_base: std::time::Duration::ZERO,
+            ..RetryPolicy::default()
+        };
+        let proxy = mk_proxy(&[closed, ok], Some(policy));
+
+        let (status, _) = send(&proxy, mk_req(Method::GET, "")).await;
+        assert_eq!(status, StatusCode::OK, "Connect errors are retried");
+    }
+
+    #[tokio::test]
+    async fn oversized_body_not_retried() {
+        let failing = echo_server(StatusCode::SERVICE_UNAVAILABLE);
+        let ok = echo_server(StatusCode::OK);
+        let policy = RetryPolicy {
+            max_body_bytes: 4,
+            ..retry_503()
+        };
+        let proxy = mk_proxy(&[failing, ok], Some(policy));
+
+        let (status, body) = send(&proxy, mk_req(Method::POST, "payload")).await;
+        assert_eq!(
+            status,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Requests with bodies over the buffer limit are not retried"
+        );
+        assert_eq!(
+            body,
+            Bytes::from("payload"),
+            "Request bodies over the buffer limit are streamed intact"
+        );
+    }
+
+    #[tokio::test]
+    async fn first_byte_timeout() {
+        let slow = slow_server(Duration::from_secs(5));
+        let timeouts = Timeouts {
+            first_byte: Some(Duration::from_millis(50)),
+            ..Timeouts::default()
+        };
+        let proxy = Proxy::builder()
+            .upstream(upstream(slow))
+            .timeouts(timeouts)
+            .build()
+            .unwrap();
+
+        let clients = Clients::new(ClientConfig::default());
+        let res = forward(&proxy, mk_req(Method::GET, ""), &clients).await;
+        assert!(
+            matches!(res, Err(ProxyError::Timeout)),
+            "Responses slower than the first byte timeout time out"
+        );
+
+        let ok = echo_server(StatusCode::OK);
+        let proxy = Proxy::builder()
+            .upstream(upstream(slow))
+            .upstream(upstream(ok))
+            .timeouts(timeouts)
+            .retry(RetryPolicy::default())
+            .build()
+            .unwrap();
+
+        let (status, _) = send(&proxy, mk_req(Method::GET, "")).await;
+        assert_eq!(status, StatusCode::OK, "Timeouts are retried");
+    }
+
+    #[tokio::test]
+    async fn default_timeouts() {
+        let slow = slow_server(Duration::from_secs(5));
+        let proxy = Proxy::builder().upstream(upstream(slow)).build().unwrap();
+
+        let clients = Clients::new(ClientConfig {
+            timeouts: Timeouts {
+                total: Some(Duration::from_millis(50)),
+                ..Timeouts::default()
+            },
+            ..ClientConfig::default()
+        });
+        let res = forward(&proxy, mk_req(Method::GET, ""), &clients).await;
+        assert!(
+            matches!(res, Err(ProxyError::Timeout)),
+            "Default timeouts apply to proxies without their own"
+        );
+    }
+
+    /// Start a TLS server with a self-signed certificate for `name`, returning the certificate.
+    async fn tls_server(name: &str) -> (SocketAddr, CertPaths) {
+        let dir = std::env::temp_dir().join(format!("warden-forward-{}", std::process::id()));
+        let cert = self_signed(&dir, name, &[name]);
+        let settings = TlsSettings {
+            certs: vec![cert.clone()],
+            min_version: TlsVersion::Tls12,
+            alpn: vec![String::from("http/1.1")],
+            client_auth: None,
+        };
+
+        let listener = Listener::bind(
+            ([127, 0, 0, 1], 0).into(),
+            Some(settings.acceptor(None).unwrap()),
+        )
+        .await
+        .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(listener.serve(tower::service_fn(|_: Request<Body>| async {
+            Ok::<_, Infallible>(Response::new(Body::from("secure")))
+        })));
+
+        (addr, cert)
+    }
+
+    #[tokio::test]
+    async fn upstream_tls() {
+        let (addr, cert) = tls_server("upstream.internal").await;
+        let ca = read_certs(&cert.cert).unwrap().remove(0).0;
+        let clients = Clients::new(ClientConfig::default());
+
+        let proxy = |tls: Option<UpstreamTls>| {
+            let https = Upstream::new(
+                String::from("https"),
+                addr.ip().to_string(),
+                Some(addr.port()),
+            );
+            let builder = Proxy::builder().upstream(https);
+            match tls {
+                Some(tls) => builder.tls(tls).build().unwrap(),
+                None => builder.build().unwrap(),
+            }
+        };
+
+        let res = forward(&proxy(None), mk_req(Method::GET, ""), &clients).await;
+        assert!(
+            matches!(res, Err(ProxyError::Tls(_))),
+            "Untrusted certificates are rejected"
+        );
+
+        let tls = UpstreamTls::new().ca(ca.clone());
+        let res = forward(&proxy(Some(tls)), mk_req(Method::GET, ""), &clients).await;
+        assert!(
+            matches!(res, Err(ProxyError::Tls(_))),
+            "Certificates are verified against the upstream host"
+        );
+
+        let tls = UpstreamTls::new()
+            .ca(ca)
+            .server_name(String::from("upstream.internal"));
+        let (status, body) = send(&proxy(Some(tls)), mk_req(Method::GET, "")).await;
+        assert_eq!(
+            (status, body),
+            (StatusCode::OK, Bytes::from("secure")),
+            "The server name can be overridden"
+        );
+
+        let tls = UpstreamTls::new().insecure_skip_verify(true);
+        let (status, _) = send(&proxy(Some(tls)), mk_req(Method::GET, "")).await;
+        assert_eq!(status, StatusCode::OK, "Verification can be skipped");
+
+        std::fs::remove_file(&cert.cert).unwrap();
+        std::fs::remove_file(&cert.key).unwrap();
+    }
+}
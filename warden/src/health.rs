@@ -0,0 +1,302 @@
+use crate::action::proxy::Proxy;
+use crate::action::upstream::Upstream;
+use crate::client::HttpClient;
+use http::{Request, StatusCode, Uri};
+use hyper::Body;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+/// Periodic HTTP GET probe of every upstream of a proxy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HealthCheck {
+    pub path: String,
+    /// Status expected from a healthy upstream; any 2xx status when not set.
+    pub expected_status: Option<StatusCode>,
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// Consecutive successful probes before an unhealthy upstream is used again.
+    pub rise: u32,
+    /// Consecutive failed probes before a healthy upstream is skipped.
+    pub fall: u32,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            path: String::from("/"),
+            expected_status: None,
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(2),
+            rise: 2,
+            fall: 3,
+        }
+    }
+}
+
+impl HealthCheck {
+    fn accepts(&self, status: StatusCode) -> bool {
+        match self.expected_status {
+            Some(expected) => status == expected,
+            None => status.is_success(),
+        }
+    }
+}
+
+/// Health of a single upstream. Upstreams start healthy and only change state once a run of
+/// probe results crosses the check's rise or fall threshold.
+pub struct HealthState {
+    healthy: AtomicBool,
+    streak: Mutex<Streak>,
+}
+
+#[derive(Default)]
+struct Streak {
+    successes: u32,
+    failures: u32,
+}
+
+impl Default for HealthState {
+    fn default() -> Self {
+        Self {
+            healthy: AtomicBool::new(true),
+            streak: Mutex::new(Streak::default()),
+        }
+    }
+}
+
+impl HealthState {
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Record a probe result, returning the new health if it changed.
+    pub fn record(&self, check: &HealthCheck, success: bool) -> Option<bool> {
+        let mut streak = self.streak.lock().expect("health lock not poisoned");
+        let healthy = self.is_healthy();
+
+        if success {
+            streak.successes += 1;
+            streak.failures = 0;
+        } else {
+            streak.failures += 1;
+            streak.successes = 0;
+        }
+
+        let changed = if healthy && streak.failures >= check.fall {
+            Some(false)
+        } else if !healthy && streak.successes >= check.rise {
+            Some(true)
+        } else {
+            None
+        };
+
+        if let Some(h) = changed {
+            self.healthy.store(h, Ordering::Relaxed);
+        }
+
+        changed
+    }
+
+    /// Take on the health and probe streak of `previous`.
+    fn inherit(&self, previous: &HealthState) {
+        let streak = previous.streak.lock().expect("health lock not poisoned");
+        let successes = streak.successes;
+        let failures = streak.failures;
+        let healthy = previous.is_healthy();
+        drop(streak);
+
+        let mut own = self.streak.lock().expect("health lock not poisoned");
+        own.successes = successes;
+        own.failures = failures;
+        self.healthy.store(healthy, Ordering::Relaxed);
+    }
+}
+
+/// Start the upstreams of `proxy` from the health they had in `previous` proxies with the same
+/// health check, so reloading a ruleset doesn't send traffic to upstreams already known to be down.
+pub fn inherit(proxy: &Proxy, previous: &[&Proxy]) {
+    let check = match proxy.health_check() {
+        Some(c) => c,
+        None => return,
+    };
+
+    for upstream in proxy.upstreams() {
+        let known = previous
+            .iter()
+            .filter(|p| p.health_check() == Some(check))
+            .flat_map(|p| p.upstreams())
+            .find(|u| u.scheme() == upstream.scheme() && u.authority() == upstream.authority());
+
+        if let Some(known) = known {
+            upstream.health().inherit(known.health());
+        }
+    }
+}
+
+/// Start probing every upstream of `proxy` that has a health check. Probing stops once the
+/// proxy is dropped, e.g. when the ruleset is reloaded.
+pub fn watch(proxy: &Proxy, client: &HttpClient) {
+    let check = match proxy.health_check() {
+        Some(c) => c,
+        None => return,
+    };
+
+    for upstream in proxy.upstreams() {
+        let uri = match probe_uri(upstream, &check.path) {
+            Some(u) => u,
+            None => {
+                tracing::error!("invalid health check path {}", check.path);
+                return;
+            }
+        };
+
+        tokio::spawn(probe_loop(
+            check.clone(),
+            uri,
+            Arc::downgrade(upstream.health()),
+            client.clone(),
+        ));
+    }
+}
+
+fn probe_uri(upstream: &Upstream, path: &str) -> Option<Uri> {
+    Uri::builder()
+        .scheme(upstream.scheme())
+        .authority(upstream.authority().as_str())
+        .path_and_query(path)
+        .build()
+        .ok()
+}
+
+async fn probe_loop(check: HealthCheck, uri: Uri, state: Weak<HealthState>, client: HttpClient) {
+    let mut ticks = tokio::time::interval(check.interval);
+
+    loop {
+        ticks.tick().await;
+
+        let success = probe(&check, &uri, &client).await;
+
+        let state = match state.upgrade() {
+            Some(s) => s,
+            None => return,
+        };
+
+        match state.record(&check, success) {
+            Some(true) => tracing::info!("upstream {} is healthy", uri),
+            Some(false) => tracing::warn!("upstream {} is unhealthy", uri),
+            None => (),
+        }
+    }
+}
+
+async fn probe(check: &HealthCheck, uri: &Uri, client: &HttpClient) -> bool {
+    let req = match Request::get(uri.clone()).body(Body::empty()) {
+        Ok(r) => r,
+        Err(_) => return false,
+    };
+
+    match tokio::time::timeout(check.timeout, client.request(req)).await {
+        Ok(Ok(res)) => check.accepts(res.status()),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rise_and_fall() {
+        let check = HealthCheck {
+            rise: 2,
+            fall: 3,
+            ..HealthCheck::default()
+        };
+        let state = HealthState::default();
+
+        assert!(state.is_healthy(), "Upstreams start healthy");
+
+        assert_eq!(state.record(&check, false), None);
+        assert_eq!(state.record(&check, false), None);
+        assert_eq!(state.record(&check, true), None);
+        assert!(state.is_healthy(), "A success resets the failure streak");
+
+        assert_eq!(state.record(&check, false), None);
+        assert_eq!(state.record(&check, false), None);
+        assert_eq!(
+            state.record(&check, false),
+            Some(false),
+            "Upstreams become unhealthy after `fall` consecutive failures"
+        );
+        assert!(!state.is_healthy());
+
+        assert_eq!(state.record(&check, true), None);
+        assert_eq!(
+            state.record(&check, true),
+            Some(true),
+            "Upstreams become healthy after `rise` consecutive successes"
+        );
+        assert!(state.is_healthy());
+    }
+
+    #[test]
+    fn inherit_health() {
+        let check = HealthCheck {
+            fall: 1,
+            ..HealthCheck::default()
+        };
+        let proxy = |check: &HealthCheck| {
+            Proxy::builder()
+                .scheme(String::from("http"))
+                .host(String::from("a.internal"))
+                .upstream(Upstream::new(
+                    String::from("http"),
+                    String::from("b.internal"),
+                    None,
+                ))
+                .health_check(check.clone())
+                .build()
+                .unwrap()
+        };
+
+        let previous = proxy(&check);
+        previous.upstreams()[0].health().record(&check, false);
+
+        let reloaded = proxy(&check);
+        inherit(&reloaded, &[&previous]);
+        assert!(
+            !reloaded.upstreams()[0].is_healthy(),
+            "Unchanged upstreams keep their health across reloads"
+        );
+        assert!(
+            reloaded.upstreams()[1].is_healthy(),
+            "Upstreams only inherit their own health"
+        );
+
+        let changed = HealthCheck {
+            path: String::from("/healthz"),
+            ..check.clone()
+        };
+        let rechecked = proxy(&changed);
+        inherit(&rechecked, &[&previous]);
+        assert!(
+            rechecked.upstreams()[0].is_healthy(),
+            "Upstreams start healthy when their health check changes"
+        );
+    }
+
+    #[test]
+    fn expected_status() {
+        let any_success = HealthCheck::default();
+        assert!(any_success.accepts(StatusCode::NO_CONTENT));
+        assert!(!any_success.accepts(StatusCode::SERVICE_UNAVAILABLE));
+
+        let exact = HealthCheck {
+            expected_status: Some(StatusCode::IM_A_TEAPOT),
+            ..HealthCheck::default()
+        };
+        assert!(exact.accepts(StatusCode::IM_A_TEAPOT));
+        assert!(!exact.accepts(StatusCode::OK));
+    }
+}
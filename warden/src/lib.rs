@@ -1,4 +1,16 @@
 pub mod action;
 pub mod agent;
 pub mod args;
+pub mod circuit;
+pub mod client;
+pub mod config;
+pub mod conn;
+pub mod error;
+pub mod forward;
+pub mod health;
+pub mod listener;
+pub mod retry;
+pub mod timeout;
+pub mod tls;
 pub mod trigger;
+pub mod watch;
@@ -0,0 +1,495 @@
+use crate::conn::{ClientAddr, TlsInfo};
+use crate::tls::{self, Acceptor, TlsSettings};
+use http::{Request, Response};
+use hyper::body::HttpBody;
+use hyper::server::conn::Http;
+use hyper::Body;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tower::{Service, ServiceBuilder};
+use tower_http::add_extension::AddExtensionLayer;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Pause after failing to accept a connection, e.g. when out of file descriptors, before trying
+/// again.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Default time allowed for a client to complete the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// HTTP versions accepted on a listener. Over TLS, the version negotiated with ALPN wins.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocols {
+    #[default]
+    Auto,
+    Http1Only,
+    Http2Only,
+}
+
+impl Protocols {
+    /// ALPN protocols to offer, in order of preference.
+    pub fn alpn(self) -> Vec<String> {
+        let alpn: &[&str] = match self {
+            Self::Auto => &["h2", "http/1.1"],
+            Self::Http1Only => &["http/1.1"],
+            Self::Http2Only => &["h2"],
+        };
+
+        alpn.iter().map(|p| p.to_string()).collect()
+    }
+
+    fn http(self, alpn: Option<&str>) -> Http {
+        let mut http = Http::new();
+        match (alpn, self) {
+            (Some("h2"), _) | (None, Self::Http2Only) => http.http2_only(true),
+            (Some("http/1.1"), _) | (None, Self::Http1Only) => http.http1_only(true),
+            _ => &mut http,
+        };
+
+        http
+    }
+}
+
+/// Where and how a listener accepts connections.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListenerSettings {
+    pub addr: SocketAddr,
+    pub tls: Option<TlsSettings>,
+    pub protocols: Protocols,
+}
+
+/// A bound socket accepting HTTP connections, optionally terminating TLS.
+pub struct Listener {
+    listener: TcpListener,
+    tls: Option<Acceptor>,
+    protocols: Protocols,
+    handshake_timeout: Duration,
+}
+
+impl Listener {
+    pub async fn bind(addr: SocketAddr, tls: Option<Acceptor>) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+            tls,
+            protocols: Protocols::Auto,
+            handshake_timeout: TLS_HANDSHAKE_TIMEOUT,
+        })
+    }
+
+    pub fn protocols(self, protocols: Protocols) -> Self {
+        Self { protocols, ..self }
+    }
+
+    /// Time allowed for a client to complete the TLS handshake before it is disconnected.
+    pub fn handshake_timeout(self, handshake_timeout: Duration) -> Self {
+        Self {
+            handshake_timeout,
+            ..self
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serve connections forever. Requests carry the client's [`ClientAddr`], and [`TlsInfo`] if
+    /// they arrived over TLS. Errors accepting connections are logged and retried.
+    pub async fn serve<S, B>(self, service: S)
+    where
+        S: Service<Request<Body>, Response = Response<B>> + Clone + Send + 'static,
+        S::Error: Into<BoxError>,
+        S::Future: Send + 'static,
+        B: HttpBody + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<BoxError>,
+    {
+        loop {
+            let (stream, remote_addr) = match self.listener.accept().await {
+                Ok(conn) => conn,
+                Err(err) if is_connection_error(&err) => {
+                    tracing::debug!("accepted connection already closed: {}", err);
+                    continue;
+                }
+                Err(err) => {
+                    tracing::error!("cannot accept connection: {}", err);
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+            let service = ServiceBuilder::new()
+                .layer(AddExtensionLayer::new(ClientAddr(remote_addr)))
+                .service(service.clone());
+
+            match &self.tls {
+                None => {
+                    tokio::spawn(serve_plain(stream, self.protocols, service));
+                }
+                Some(acceptor) => {
+                    tokio::spawn(serve_tls(
+                        acceptor.clone(),
+                        self.handshake_timeout,
+                        stream,
+                        remote_addr,
+                        self.protocols,
+                        service,
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Errors for a single connection that was closed before it was accepted, rather than for the
+/// listener.
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+async fn serve_plain<S, B>(stream: TcpStream, protocols: Protocols, service: S)
+where
+    S: Service<Request<Body>, Response = Response<B>> + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    if let Err(err) = protocols
+        .http(None)
+        .serve_connection(stream, service)
+        .with_upgrades()
+        .await
+    {
+        tracing::debug!("connection error: {}", err);
+    }
+}
+
+async fn serve_tls<S, B>(
+    acceptor: Acceptor,
+    handshake_timeout: Duration,
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+    protocols: Protocols,
+    service: S,
+) where
+    S: Service<Request<Body>, Response = Response<B>> + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    let accept = acceptor.accept(stream);
+    let stream = match tokio::time::timeout(handshake_timeout, accept).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(err)) => {
+            tracing::debug!("TLS handshake with {} failed: {}", remote_addr, err);
+            return;
+        }
+        Err(_) => {
+            tracing::debug!("TLS handshake with {} timed out", remote_addr);
+            return;
+        }
+    };
+
+    let (_, session) = stream.get_ref();
+    let info = TlsInfo {
+        server_name: session.server_name().map(String::from),
+        alpn: session
+            .alpn_protocol()
+            .map(|p| String::from_utf8_lossy(p).into_owned()),
+        client_cert: session
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| tls::client_cert(&cert.0)),
+    };
+
+    let http = protocols.http(info.alpn.as_deref());
+
+    let service = ServiceBuilder::new()
+        .layer(AddExtensionLayer::new(info))
+        .service(service);
+
+    if let Err(err) = http.serve_connection(stream, service).with_upgrades().await {
+        tracing::debug!("connection error: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn::client_scheme;
+    use crate::tls::tests::{authority, client_signed, self_signed};
+    use crate::tls::{
+        read_certs, read_key, CertPaths, ClientAuth, ClientAuthMode, TlsSettings, TlsVersion,
+    };
+    use rustls::{ClientConfig, RootCertStore, ServerName};
+    use std::convert::Infallible;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    async fn describe(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let info = req
+            .extensions()
+            .get::<TlsInfo>()
+            .cloned()
+            .unwrap_or_default();
+        let mut description = format!(
+            "{} {} {}",
+            client_scheme(&req),
+            info.server_name.unwrap_or_default(),
+            info.alpn.unwrap_or_default()
+        );
+        if let Some(cert) = info.client_cert {
+            description.push_str(&format!(" {}", cert.subject));
+        }
+
+        Ok(Response::new(Body::from(description)))
+    }
+
+    async fn tls_listener(
+        certs: Vec<CertPaths>,
+        alpn: &[&str],
+        client_auth: Option<ClientAuth>,
+    ) -> SocketAddr {
+        let settings = TlsSettings {
+            certs,
+            min_version: TlsVersion::Tls12,
+            alpn: alpn.iter().map(|p| p.to_string()).collect(),
+            client_auth,
+        };
+        let listener = Listener::bind(
+            ([127, 0, 0, 1], 0).into(),
+            Some(settings.acceptor(None).unwrap()),
+        )
+        .await
+        .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(listener.serve(tower::service_fn(describe)));
+
+        addr
+    }
+
+    fn connector(trusted: &CertPaths, alpn: &[&str]) -> TlsConnector {
+        client_connector(trusted, alpn, None)
+    }
+
+    fn client_connector(
+        trusted: &CertPaths,
+        alpn: &[&str],
+        identity: Option<&CertPaths>,
+    ) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        for cert in read_certs(&trusted.cert).unwrap() {
+            roots.add(&cert).unwrap();
+        }
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let mut config = match identity {
+            Some(paths) => builder
+                .with_client_auth_cert(
+                    read_certs(&paths.cert).unwrap(),
+                    read_key(&paths.key).unwrap(),
+                )
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+
+        TlsConnector::from(Arc::new(config))
+    }
+
+    async fn get(addr: SocketAddr, connector: TlsConnector, name: &str) -> io::Result<String> {
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = connector
+            .connect(ServerName::try_from(name).unwrap(), stream)
+            .await?;
+
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: warden\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+
+        Ok(response
+            .rsplit("\r\n")
+            .next()
+            .unwrap_or_default()
+            .to_string())
+    }
+
+    #[tokio::test]
+    async fn tls_termination() {
+        let dir = std::env::temp_dir().join(format!("warden-listener-{}", std::process::id()));
+        let a = self_signed(&dir, "a", &["a.example.com"]);
+        let b = self_signed(&dir, "b", &["b.example.com"]);
+        let addr = tls_listener(vec![a.clone(), b.clone()], &["h2", "http/1.1"], None).await;
+
+        assert_eq!(
+            get(addr, connector(&b, &["http/1.1"]), "b.example.com")
+                .await
+                .unwrap(),
+            "https b.example.com http/1.1",
+            "The certificate is chosen by SNI and ALPN is negotiated"
+        );
+        assert!(
+            get(addr, connector(&b, &["http/1.1"]), "c.example.com")
+                .await
+                .is_err(),
+            "Unknown names get the default certificate"
+        );
+        assert_eq!(
+            get(addr, connector(&a, &[]), "a.example.com")
+                .await
+                .unwrap(),
+            "https a.example.com ",
+            "Clients without ALPN are served"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stalled_handshake() {
+        let dir = std::env::temp_dir().join(format!("warden-handshake-{}", std::process::id()));
+        let cert = self_signed(&dir, "server", &["warden.example.com"]);
+        let settings = TlsSettings {
+            certs: vec![cert],
+            min_version: TlsVersion::Tls12,
+            alpn: Vec::new(),
+            client_auth: None,
+        };
+        let listener = Listener::bind(
+            ([127, 0, 0, 1], 0).into(),
+            Some(settings.acceptor(None).unwrap()),
+        )
+        .await
+        .unwrap()
+        .handshake_timeout(Duration::from_millis(50));
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(listener.serve(tower::service_fn(describe)));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await;
+        assert!(
+            matches!(read, Ok(Ok(0)) | Ok(Err(_))),
+            "Clients that don't complete the handshake are disconnected"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_certificates() {
+        let dir = std::env::temp_dir().join(format!("warden-mtls-{}", std::process::id()));
+        let server = self_signed(&dir, "server", &["warden.example.com"]);
+        let (ca, ca_paths) = authority(&dir, "ca");
+        let client = client_signed(&dir, "svc-a", &ca, &["svc-a.internal"]);
+        let (other_ca, _) = authority(&dir, "other-ca");
+        let stranger = client_signed(&dir, "stranger", &other_ca, &["stranger.internal"]);
+
+        let auth = |mode| {
+            Some(ClientAuth {
+                ca: ca_paths.cert.clone(),
+                mode,
+            })
+        };
+        let required = tls_listener(vec![server.clone()], &[], auth(ClientAuthMode::Require)).await;
+        let requested =
+            tls_listener(vec![server.clone()], &[], auth(ClientAuthMode::Request)).await;
+
+        let name = "warden.example.com";
+        assert_eq!(
+            get(
+                required,
+                client_connector(&server, &[], Some(&client)),
+                name
+            )
+            .await
+            .unwrap(),
+            "https warden.example.com  CN=svc-a",
+            "Verified client certificates are passed to the service"
+        );
+        assert!(
+            get(required, connector(&server, &[]), name).await.is_err(),
+            "Clients without a certificate are rejected when one is required"
+        );
+        assert!(
+            get(
+                required,
+                client_connector(&server, &[], Some(&stranger)),
+                name
+            )
+            .await
+            .is_err(),
+            "Certificates from other authorities are rejected"
+        );
+        assert_eq!(
+            get(requested, connector(&server, &[]), name).await.unwrap(),
+            "https warden.example.com ",
+            "Clients may omit a certificate when one is only requested"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_ca_reload() {
+        let dir = std::env::temp_dir().join(format!("warden-ca-reload-{}", std::process::id()));
+        let server = self_signed(&dir, "server", &["warden.example.com"]);
+        let (ca, ca_paths) = authority(&dir, "ca");
+        let client = client_signed(&dir, "svc-a", &ca, &["svc-a.internal"]);
+        let (other_ca, other_ca_paths) = authority(&dir, "other-ca");
+        let stranger = client_signed(&dir, "stranger", &other_ca, &["stranger.internal"]);
+
+        let settings = TlsSettings {
+            certs: vec![server.clone()],
+            min_version: TlsVersion::Tls12,
+            alpn: Vec::new(),
+            client_auth: Some(ClientAuth {
+                ca: ca_paths.cert.clone(),
+                mode: ClientAuthMode::Require,
+            }),
+        };
+        let acceptor = settings.acceptor(Some(Duration::from_millis(20))).unwrap();
+        let listener = Listener::bind(([127, 0, 0, 1], 0).into(), Some(acceptor))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(listener.serve(tower::service_fn(describe)));
+
+        let name = "warden.example.com";
+        let stranger = || client_connector(&server, &[], Some(&stranger));
+        assert!(get(addr, stranger(), name).await.is_err());
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        std::fs::copy(&other_ca_paths.cert, &ca_paths.cert).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(
+            get(addr, stranger(), name).await.unwrap(),
+            "https warden.example.com  CN=stranger",
+            "The client CA bundle is reloaded when it changes"
+        );
+        assert!(
+            get(addr, client_connector(&server, &[], Some(&client)), name)
+                .await
+                .is_err(),
+            "Authorities removed from the bundle are no longer trusted"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,79 +1,212 @@
 use clap::Parser;
+use futures_util::future::join_all;
 use http::header;
-use http::{Request, Response};
-use http::response;
-use hyper::{Body, Client, Error, Server};
-use hyper_tls::HttpsConnector;
-use std::convert::From;
+use http::header::HeaderName;
+use http::{Request, Response, StatusCode};
+use hyper::Body;
+use std::convert::{From, Infallible};
 use std::iter::once;
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
-use tower::make::Shared;
+use std::time::Duration;
 use tower::ServiceBuilder;
 use tower_http::add_extension::AddExtensionLayer;
 use tower_http::cors::CorsLayer;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
 use tower_http::sensitive_headers::SetSensitiveRequestHeadersLayer;
 use tower_http::trace::TraceLayer;
+use warden::action::Action;
 use warden::agent;
-use warden::agent::Ruleset;
+use warden::agent::RulesetHandle;
 use warden::args::Args;
+use warden::client::{ClientConfig, Clients};
+use warden::conn::REQUEST_ID;
+use warden::error::ErrorPage;
+use warden::forward;
+use warden::listener::{Listener, ListenerSettings, Protocols};
+use warden::tls::TlsSettings;
 
 #[tokio::main]
 pub async fn main() {
     let args = Args::parse();
 
-    let ruleset = agent::start(&args);
-
     let tracing_filter = format!("{},hyper=error,mio=error", args.log_level);
     tracing_subscriber::fmt::fmt()
         .with_env_filter(tracing_filter)
         .init();
 
+    let clients = Clients::new(ClientConfig::from(&args));
+
+    let rulesets = match agent::start(&args, &clients) {
+        Ok(rulesets) => rulesets,
+        Err(err) => {
+            eprintln!("config error: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let error_page = match &args.error_template {
+        Some(path) => match ErrorPage::load(path) {
+            Ok(page) => page,
+            Err(err) => {
+                eprintln!("error template error: {}", err);
+                std::process::exit(1);
+            }
+        },
+        None => ErrorPage::default(),
+    };
+
+    let request_id = HeaderName::from_static(REQUEST_ID);
+
     let service = ServiceBuilder::new()
         .layer(SetSensitiveRequestHeadersLayer::new(once(
             header::AUTHORIZATION,
         )))
+        .layer(SetRequestIdLayer::new(request_id.clone(), MakeRequestUuid))
+        .layer(PropagateRequestIdLayer::new(request_id))
         .layer(TraceLayer::new_for_http())
         .layer(CorsLayer::permissive())
-        .layer(AddExtensionLayer::new(ruleset))
+        .layer(AddExtensionLayer::new(clients))
+        .layer(AddExtensionLayer::new(error_page))
         .service_fn(handler);
 
-    let addr = SocketAddr::from((
-        IpAddr::from_str(args.addr.as_str()).expect("Valid IP address specified"),
-        args.port,
-    ));
+    let mut listeners = Vec::new();
+
+    if let Some(port) = args.port {
+        let addr = SocketAddr::from((
+            IpAddr::from_str(args.addr.as_str()).expect("Valid IP address specified"),
+            port,
+        ));
 
-    let server = Server::bind(&addr).serve(Shared::new(service));
+        let tls = match TlsSettings::from_args(&args) {
+            Ok(tls) => tls,
+            Err(err) => {
+                eprintln!("TLS error: {}", err);
+                std::process::exit(1);
+            }
+        };
+
+        let settings = ListenerSettings {
+            addr,
+            tls,
+            protocols: Protocols::Auto,
+        };
+        listeners.push((settings, rulesets.shared.clone()));
+    }
+
+    for listener in rulesets.listeners {
+        listeners.push((listener.settings, listener.rules));
+    }
 
-    if let Err(err) = server.await {
-        eprintln!("server error: {}", err);
+    if listeners.is_empty() {
+        eprintln!("no listeners: give --port or define listeners in the config file");
+        std::process::exit(1);
     }
+
+    let mut servers = Vec::new();
+    for (settings, rules) in listeners {
+        let listener = match bind(&settings, poll_interval(args.config_poll_secs)).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                eprintln!("cannot listen on {}: {}", settings.addr, err);
+                std::process::exit(1);
+            }
+        };
+        tracing::info!("listening on {}", settings.addr);
+
+        let service = ServiceBuilder::new()
+            .layer(AddExtensionLayer::new(rules))
+            .service(service.clone());
+        servers.push(listener.serve(service));
+    }
+
+    join_all(servers).await;
+}
+
+async fn bind(
+    settings: &ListenerSettings,
+    cert_poll: Option<Duration>,
+) -> Result<Listener, String> {
+    let tls = match &settings.tls {
+        Some(tls) => Some(tls.acceptor(cert_poll).map_err(|e| e.to_string())?),
+        None => None,
+    };
+
+    let listener = Listener::bind(settings.addr, tls)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(listener.protocols(settings.protocols))
+}
+
+fn poll_interval(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
 }
 
-async fn handler(req: Request<Body>) -> Result<Response<Body>, Error> {
+async fn handler(mut req: Request<Body>) -> Result<Response<Body>, Infallible> {
     let ruleset = req
         .extensions()
-        .get::<Ruleset>()
+        .get::<RulesetHandle>()
         .expect("ruleset available")
+        .current();
+
+    let error_page = req
+        .extensions()
+        .get::<ErrorPage>()
+        .expect("error page available")
         .clone();
 
-    match ruleset.iter().find(|r| r.applies(&req)) {
-        None => Ok(err_404()),
-        Some(rule) => {
-            let https = HttpsConnector::new();
-            let client = Client::builder().build::<_, hyper::Body>(https);
+    let request_id = req.headers().get(REQUEST_ID).cloned();
+
+    match ruleset.iter().find_map(|r| Some((r, r.matches(&req)?))) {
+        None => Ok(error_page.render(
+            StatusCode::NOT_FOUND,
+            "no rule matches the request",
+            request_id.as_ref(),
+        )),
+        Some((rule, params)) => {
+            req.extensions_mut().insert(params);
+
+            let clients = req
+                .extensions()
+                .get::<Clients>()
+                .expect("clients available")
+                .clone();
 
-            match rule.transform_req(req) {
-                None => Ok(err_404()),
-                Some(r) => client.request(r).await,
+            match rule.action() {
+                Action::Proxy(proxy) => match forward::forward(proxy, req, &clients).await {
+                    Ok(res) => Ok(res),
+                    Err(err) => {
+                        let id = request_id.as_ref().and_then(|id| id.to_str().ok()).unwrap_or("-");
+                        match std::error::Error::source(&err) {
+                            Some(cause) => tracing::warn!(request_id = id, "{}: {}", err, cause),
+                            None => tracing::warn!(request_id = id, "{}", err),
+                        }
+
+                        Ok(error_page.render(err.status(), &err.to_string(), request_id.as_ref()))
+                    }
+                },
+                Action::Redirect(redirect) => match redirect.response(&req) {
+                    Ok(res) => Ok(res),
+                    Err(err) => Ok(error_page.render(
+                        err.status(),
+                        &err.to_string(),
+                        request_id.as_ref(),
+                    )),
+                },
+                Action::Respond(respond) => Ok(respond.response()),
+                Action::ServeDir(serve_dir) => match serve_dir.serve(&req).await {
+                    Ok(res) => Ok(res),
+                    Err(err) => {
+                        tracing::warn!("could not serve file: {}", err);
+                        Ok(error_page.render(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "could not serve file",
+                            request_id.as_ref(),
+                        ))
+                    }
+                },
             }
         },
     }
 }
-
-fn err_404() -> Response<Body> {
-    response::Builder::new()
-        .status(404)
-        .body(Body::from("Not found"))
-        .expect("can construct 404 response")
-}
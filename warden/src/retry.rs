@@ -0,0 +1,206 @@
+use crate::trigger::method::MethodTrigger;
+use http::{Method, Request, StatusCode};
+use rand::Rng;
+use std::time::Duration;
+use tower::retry::budget::Budget;
+
+/// A failed attempt to forward a request that a `RetryPolicy` may retry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    Connect,
+    Timeout,
+    Status(StatusCode),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RetryOn {
+    ConnectError,
+    Timeout,
+    Status(StatusCode),
+}
+
+impl RetryOn {
+    fn matches(&self, failure: Failure) -> bool {
+        match (self, failure) {
+            (Self::ConnectError, Failure::Connect) => true,
+            (Self::Timeout, Failure::Timeout) => true,
+            (Self::Status(s), Failure::Status(f)) => *s == f,
+            _ => false,
+        }
+    }
+}
+
+pub struct RetryPolicy {
+    /// Attempts per request, including the first.
+    pub max_attempts: u32,
+    pub retry_on: Vec<RetryOn>,
+    /// Methods whose requests may be retried.
+    pub methods: MethodTrigger,
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    /// Largest request body that is buffered for replay; larger requests are sent only once.
+    pub max_body_bytes: usize,
+    /// Limits retries across all requests, so that retries cannot multiply load on a failing
+    /// upstream.
+    pub budget: Budget,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            retry_on: vec![RetryOn::ConnectError, RetryOn::Timeout],
+            methods: MethodTrigger::OneOf(idempotent_methods()),
+            backoff_base: Duration::from_millis(25),
+            backoff_max: Duration::from_secs(1),
+            max_body_bytes: 64 * 1024,
+            budget: default_budget(),
+        }
+    }
+}
+
+pub fn idempotent_methods() -> Vec<Method> {
+    vec![
+        Method::GET,
+        Method::HEAD,
+        Method::OPTIONS,
+        Method::TRACE,
+        Method::PUT,
+        Method::DELETE,
+    ]
+}
+
+/// Seconds over which the default retry budget counts requests.
+pub const BUDGET_TTL_SECS: u64 = 10;
+/// Retries per second the default retry budget always allows.
+pub const BUDGET_MIN_PER_SEC: u32 = 10;
+/// Share of requests the default retry budget allows to be retried.
+pub const BUDGET_RETRY_PERCENT: f32 = 0.2;
+
+/// Retries of up to 20% of requests over a 10 second window, plus 10 per second.
+pub fn default_budget() -> Budget {
+    Budget::new(
+        Duration::from_secs(BUDGET_TTL_SECS),
+        BUDGET_MIN_PER_SEC,
+        BUDGET_RETRY_PERCENT,
+    )
+}
+
+impl RetryPolicy {
+    /// Whether a request may be retried at all, judged by its method.
+    pub fn allows<T>(&self, req: &Request<T>) -> bool {
+        self.max_attempts > 1 && self.methods.applies(req)
+    }
+
+    /// Whether to make another attempt after `failure` on attempt number `attempt`. Withdraws
+    /// from the retry budget when it says yes.
+    pub fn retries(&self, attempt: u32, failure: Failure) -> bool {
+        attempt < self.max_attempts
+            && self.retry_on.iter().any(|r| r.matches(failure))
+            && self.budget.withdraw().is_ok()
+    }
+
+    /// Exponential backoff with full jitter before attempt number `attempt + 1`.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .backoff_base
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.backoff_max);
+
+        exp.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mk_req(method: Method) -> Request<()> {
+        Request::builder().method(method).uri("/").body(()).unwrap()
+    }
+
+    #[test]
+    fn allows_idempotent_methods() {
+        let policy = RetryPolicy::default();
+
+        assert!(policy.allows(&mk_req(Method::GET)));
+        assert!(policy.allows(&mk_req(Method::PUT)));
+        assert!(
+            !policy.allows(&mk_req(Method::POST)),
+            "Only idempotent methods are retried by default"
+        );
+        assert!(
+            !policy.allows(&mk_req(Method::PATCH)),
+            "Only idempotent methods are retried by default"
+        );
+
+        let single = RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        };
+        assert!(
+            !single.allows(&mk_req(Method::GET)),
+            "A single attempt never retries"
+        );
+    }
+
+    #[test]
+    fn retries_conditions() {
+        let policy = RetryPolicy {
+            retry_on: vec![
+                RetryOn::ConnectError,
+                RetryOn::Status(StatusCode::SERVICE_UNAVAILABLE),
+            ],
+            ..RetryPolicy::default()
+        };
+
+        assert!(policy.retries(1, Failure::Connect));
+        assert!(policy.retries(2, Failure::Status(StatusCode::SERVICE_UNAVAILABLE)));
+        assert!(
+            !policy.retries(3, Failure::Connect),
+            "Retries stop at max_attempts"
+        );
+        assert!(
+            !policy.retries(1, Failure::Timeout),
+            "Only listed failures are retried"
+        );
+        assert!(
+            !policy.retries(1, Failure::Status(StatusCode::BAD_GATEWAY)),
+            "Only listed statuses are retried"
+        );
+    }
+
+    #[test]
+    fn retries_within_budget() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            budget: Budget::new(Duration::from_secs(10), 0, 0.5),
+            ..RetryPolicy::default()
+        };
+
+        (0..4).for_each(|_| policy.budget.deposit());
+
+        let retried = (0..10)
+            .filter(|_| policy.retries(1, Failure::Connect))
+            .count();
+        assert_eq!(retried, 2, "Retries are limited by the budget");
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy {
+            backoff_base: Duration::from_millis(10),
+            backoff_max: Duration::from_millis(50),
+            ..RetryPolicy::default()
+        };
+
+        for attempt in 1..10 {
+            let cap = Duration::from_millis(10 * 2u64.pow(attempt - 1)).min(policy.backoff_max);
+            let backoff = policy.backoff(attempt);
+            assert!(
+                backoff <= cap,
+                "Backoff is jittered below the exponential cap"
+            );
+        }
+    }
+}
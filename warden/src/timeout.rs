@@ -0,0 +1,197 @@
+use futures_util::Stream;
+use hyper::body::Bytes;
+use hyper::Body;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{Instant, Sleep};
+
+/// Limits on how long forwarding a request to an upstream may take. Unset limits fall back to
+/// the global defaults, and are unlimited if those are unset too. A rule disables a global
+/// default with a zero limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    pub connect: Option<Duration>,
+    /// Time from sending a request until the response headers arrive.
+    pub first_byte: Option<Duration>,
+    /// Time for the whole exchange, including retries and the response body.
+    pub total: Option<Duration>,
+    /// Longest pause between chunks of the response body.
+    pub idle_body: Option<Duration>,
+}
+
+impl Timeouts {
+    pub fn or(self, defaults: &Timeouts) -> Self {
+        let or = |limit: Option<Duration>, default: Option<Duration>| match limit {
+            Some(limit) if limit.is_zero() => None,
+            limit => limit.or(default),
+        };
+
+        Self {
+            connect: or(self.connect, defaults.connect),
+            first_byte: or(self.first_byte, defaults.first_byte),
+            total: or(self.total, defaults.total),
+            idle_body: or(self.idle_body, defaults.idle_body),
+        }
+    }
+}
+
+/// A response body that fails once it stalls for longer than the idle timeout, or runs past the
+/// deadline.
+pub struct TimeoutBody {
+    inner: Body,
+    idle: Option<Duration>,
+    deadline: Option<Instant>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl TimeoutBody {
+    pub fn new(inner: Body, idle: Option<Duration>, deadline: Option<Instant>) -> Self {
+        let mut body = Self {
+            inner,
+            idle,
+            deadline,
+            sleep: None,
+        };
+        body.sleep = body
+            .next_timeout()
+            .map(|at| Box::pin(tokio::time::sleep_until(at)));
+
+        body
+    }
+
+    /// Wrap a response body, or leave it untouched if there is no limit to enforce.
+    pub fn wrap(inner: Body, idle: Option<Duration>, deadline: Option<Instant>) -> Body {
+        match (idle, deadline) {
+            (None, None) => inner,
+            _ => Body::wrap_stream(Self::new(inner, idle, deadline)),
+        }
+    }
+
+    fn next_timeout(&self) -> Option<Instant> {
+        let idle = self.idle.map(|idle| Instant::now() + idle);
+
+        match (idle, self.deadline) {
+            (Some(idle), Some(deadline)) => Some(idle.min(deadline)),
+            (idle, deadline) => idle.or(deadline),
+        }
+    }
+}
+
+impl Stream for TimeoutBody {
+    type Item = Result<Bytes, Box<dyn Error + Send + Sync>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Poll::Ready(item) = Pin::new(&mut self.inner).poll_next(cx) {
+            if let Some(at) = self.next_timeout() {
+                if let Some(sleep) = self.sleep.as_mut() {
+                    sleep.as_mut().reset(at);
+                }
+            }
+
+            return Poll::Ready(item.map(|r| r.map_err(Into::into)));
+        }
+
+        match self.sleep.as_mut().map(|s| s.as_mut().poll(cx)) {
+            Some(Poll::Ready(())) => {
+                self.sleep = None;
+                Poll::Ready(Some(Err(Box::new(BodyTimeout))))
+            }
+            _ => Poll::Pending,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BodyTimeout;
+
+impl fmt::Display for BodyTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "response body timed out")
+    }
+}
+
+impl Error for BodyTimeout {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    #[test]
+    fn fallback() {
+        let rule = Timeouts {
+            connect: Some(Duration::from_secs(1)),
+            idle_body: Some(Duration::ZERO),
+            ..Timeouts::default()
+        };
+        let defaults = Timeouts {
+            connect: Some(Duration::from_secs(5)),
+            total: Some(Duration::from_secs(30)),
+            idle_body: Some(Duration::from_secs(60)),
+            ..Timeouts::default()
+        };
+
+        let timeouts = rule.or(&defaults);
+        assert_eq!(
+            timeouts.connect,
+            Some(Duration::from_secs(1)),
+            "Timeouts set on a rule take precedence"
+        );
+        assert_eq!(
+            timeouts.total,
+            Some(Duration::from_secs(30)),
+            "Unset timeouts fall back to the defaults"
+        );
+        assert_eq!(
+            timeouts.idle_body, None,
+            "Zero timeouts on a rule disable the defaults"
+        );
+        assert_eq!(timeouts.first_byte, None, "Timeouts are optional");
+    }
+
+    #[tokio::test]
+    async fn idle_body() {
+        let (mut tx, rx) = Body::channel();
+        let mut body = TimeoutBody::new(rx, Some(Duration::from_millis(50)), None);
+
+        tx.send_data(Bytes::from("a")).await.unwrap();
+        assert!(
+            matches!(body.next().await, Some(Ok(_))),
+            "Chunks arriving in time are passed through"
+        );
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        tx.send_data(Bytes::from("b")).await.unwrap();
+        assert!(
+            matches!(body.next().await, Some(Ok(_))),
+            "The idle timeout restarts with each chunk"
+        );
+
+        let err = body.next().await;
+        assert!(
+            matches!(err, Some(Err(_))),
+            "Bodies fail after stalling for the idle timeout"
+        );
+        drop(tx);
+    }
+
+    #[tokio::test]
+    async fn deadline() {
+        let (mut tx, rx) = Body::channel();
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let mut body = TimeoutBody::new(rx, None, Some(deadline));
+
+        tx.send_data(Bytes::from("a")).await.unwrap();
+        assert!(matches!(body.next().await, Some(Ok(_))));
+
+        assert!(
+            matches!(body.next().await, Some(Err(_))),
+            "Bodies fail once the deadline has passed"
+        );
+        drop(tx);
+    }
+}
@@ -1,28 +1,239 @@
+pub mod client_cert;
+pub mod header;
+pub mod host;
 pub mod method;
 pub mod path;
+pub mod query;
 
+use crate::trigger::client_cert::ClientCertTrigger;
+use crate::trigger::header::HeaderTrigger;
+use crate::trigger::host::HostTrigger;
 use crate::trigger::method::MethodTrigger;
-use crate::trigger::path::PathTrigger;
+use crate::trigger::path::{PathParams, PathTrigger};
+use crate::trigger::query::QueryTrigger;
 use http::Request;
 
-pub struct Trigger {
-    path: PathTrigger,
-    method: MethodTrigger,
+pub enum Trigger {
+    Path(PathTrigger),
+    Method(MethodTrigger),
+    Header(HeaderTrigger),
+    Host(HostTrigger),
+    Query(QueryTrigger),
+    ClientCert(ClientCertTrigger),
+    All(Vec<Trigger>),
+    AnyOf(Vec<Trigger>),
+    Not(Box<Trigger>),
 }
 
 impl Trigger {
     pub fn new(path: PathTrigger, method: MethodTrigger) -> Self {
-        Self { path, method }
+        Self::All(vec![Self::Path(path), Self::Method(method)])
     }
 
     pub fn catch_all() -> Self {
-        Self {
-            path: PathTrigger::Any,
-            method: MethodTrigger::Any,
+        Self::All(Vec::new())
+    }
+
+    pub fn negate(trigger: Trigger) -> Self {
+        Self::Not(Box::new(trigger))
+    }
+
+    /// Combine with another trigger, such that both must apply.
+    pub fn and(self, other: Trigger) -> Self {
+        match self {
+            Self::All(mut triggers) => {
+                triggers.push(other);
+                Self::All(triggers)
+            }
+            trigger => Self::All(vec![trigger, other]),
         }
     }
 
+    pub fn header(self, header: HeaderTrigger) -> Self {
+        self.and(Self::Header(header))
+    }
+
+    pub fn host(self, host: HostTrigger) -> Self {
+        self.and(Self::Host(host))
+    }
+
+    pub fn query(self, query: QueryTrigger) -> Self {
+        self.and(Self::Query(query))
+    }
+
+    pub fn client_cert(self, client_cert: ClientCertTrigger) -> Self {
+        self.and(Self::ClientCert(client_cert))
+    }
+
     pub fn applies<T>(&self, req: &Request<T>) -> bool {
-        self.path.applies(req) && self.method.applies(req)
+        match self {
+            Self::Path(path) => path.applies(req),
+            Self::Method(method) => method.applies(req),
+            Self::Header(header) => header.applies(req),
+            Self::Host(host) => host.applies(req),
+            Self::Query(query) => query.applies(req),
+            Self::ClientCert(client_cert) => client_cert.applies(req),
+            Self::All(triggers) => triggers.iter().all(|t| t.applies(req)),
+            Self::AnyOf(triggers) => triggers.iter().any(|t| t.applies(req)),
+            Self::Not(trigger) => !trigger.applies(req),
+        }
+    }
+
+    /// Names of the parameters `captures` may return.
+    pub fn capture_names(&self) -> Vec<&str> {
+        match self {
+            Self::Path(path) => path.capture_names(),
+            Self::All(triggers) | Self::AnyOf(triggers) => {
+                triggers.iter().flat_map(Trigger::capture_names).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Path parameters captured by the trigger tree, if it applies. Every branch of `All`
+    /// contributes, `AnyOf` takes the first branch that applies, and `Not` captures nothing.
+    pub fn captures<T>(&self, req: &Request<T>) -> Option<PathParams> {
+        match self {
+            Self::Path(path) => path.captures(req),
+            Self::All(triggers) => triggers
+                .iter()
+                .try_fold(PathParams::default(), |params, t| {
+                    Some(params.merge(t.captures(req)?))
+                }),
+            Self::AnyOf(triggers) => triggers.iter().find_map(|t| t.captures(req)),
+            _ if self.applies(req) => Some(PathParams::default()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mk_req(method: http::Method, uri: &str) -> Request<()> {
+        Request::builder().method(method).uri(uri).body(()).unwrap()
+    }
+
+    fn path(p: &str) -> Trigger {
+        Trigger::Path(PathTrigger::Exactly(String::from(p)))
+    }
+
+    #[test]
+    fn catch_all_applies() {
+        let req = mk_req(http::Method::DELETE, "http://foo.com/x?a=b");
+        assert!(
+            Trigger::catch_all().applies(&req),
+            "Trigger::catch_all applies to any request"
+        );
+    }
+
+    #[test]
+    fn all_applies() {
+        let trigger = Trigger::new(
+            PathTrigger::Exactly(String::from("/a")),
+            MethodTrigger::Exactly(http::Method::POST),
+        );
+
+        let res_y = trigger.applies(&mk_req(http::Method::POST, "/a"));
+        assert!(
+            res_y,
+            "Trigger::All applies only when every trigger applies"
+        );
+
+        let res_n = trigger.applies(&mk_req(http::Method::GET, "/a"));
+        assert!(
+            !res_n,
+            "Trigger::All applies only when every trigger applies"
+        );
+    }
+
+    #[test]
+    fn any_of_applies() {
+        // path A, or path B with POST
+        let trigger = Trigger::AnyOf(vec![
+            path("/a"),
+            Trigger::new(
+                PathTrigger::Exactly(String::from("/b")),
+                MethodTrigger::Exactly(http::Method::POST),
+            ),
+        ]);
+
+        let res_a = trigger.applies(&mk_req(http::Method::GET, "/a"));
+        assert!(res_a, "Trigger::AnyOf applies when any trigger applies");
+
+        let res_b = trigger.applies(&mk_req(http::Method::POST, "/b"));
+        assert!(res_b, "Trigger::AnyOf applies when any trigger applies");
+
+        let res_n = trigger.applies(&mk_req(http::Method::GET, "/b"));
+        assert!(
+            !res_n,
+            "Trigger::AnyOf applies only when some trigger applies"
+        );
+
+        let res_empty = Trigger::AnyOf(vec![]).applies(&mk_req(http::Method::GET, "/a"));
+        assert!(!res_empty, "Empty Trigger::AnyOf never applies");
+    }
+
+    #[test]
+    fn not_applies() {
+        let trigger = Trigger::negate(path("/health"));
+
+        let res_y = trigger.applies(&mk_req(http::Method::GET, "/x"));
+        assert!(
+            res_y,
+            "Trigger::Not applies only when the inner trigger does not"
+        );
+
+        let res_n = trigger.applies(&mk_req(http::Method::GET, "/health"));
+        assert!(
+            !res_n,
+            "Trigger::Not applies only when the inner trigger does not"
+        );
+    }
+
+    #[test]
+    fn and_applies() {
+        let trigger = path("/a").query(QueryTrigger::Present(String::from("q")));
+
+        let res_y = trigger.applies(&mk_req(http::Method::GET, "/a?q=1"));
+        assert!(res_y, "Trigger::and applies only when both triggers apply");
+
+        let res_n = trigger.applies(&mk_req(http::Method::GET, "/a"));
+        assert!(!res_n, "Trigger::and applies only when both triggers apply");
+    }
+
+    #[test]
+    fn captures() {
+        let template = path::PathTemplate::new("/users/{id}").unwrap();
+        let trigger = Trigger::AnyOf(vec![
+            Trigger::Path(PathTrigger::Exactly(String::from("/users/me"))),
+            Trigger::Path(PathTrigger::Template(template)),
+        ])
+        .query(QueryTrigger::Absent(String::from("skip")));
+
+        let me = trigger
+            .captures(&mk_req(http::Method::GET, "/users/me"))
+            .unwrap();
+        assert_eq!(
+            me.get("id"),
+            None,
+            "Trigger::AnyOf captures from the first applying branch"
+        );
+
+        let seven = trigger
+            .captures(&mk_req(http::Method::GET, "/users/7"))
+            .unwrap();
+        assert_eq!(
+            seven.get("id"),
+            Some("7"),
+            "Trigger::All captures from every branch"
+        );
+
+        let skipped = trigger.captures(&mk_req(http::Method::GET, "/users/7?skip=1"));
+        assert!(
+            skipped.is_none(),
+            "Trigger::captures is none when the trigger does not apply"
+        );
     }
 }
@@ -0,0 +1,118 @@
+use crate::conn::{ClientCert, TlsInfo};
+use http::Request;
+
+/// Matches the verified client certificate of requests received on a TLS listener.
+pub enum ClientCertTrigger {
+    Present,
+    Absent,
+    /// The subject distinguished name matches, e.g. `CN=svc-a,`.
+    Subject(regex::Regex),
+    /// A DNS, email or URI subject alternative name is equal, ignoring case.
+    San(String),
+    /// The SHA-256 fingerprint, as lowercase hex, is one of these.
+    Fingerprint(Vec<String>),
+}
+
+impl ClientCertTrigger {
+    pub fn applies<T>(&self, req: &Request<T>) -> bool {
+        let cert = req
+            .extensions()
+            .get::<TlsInfo>()
+            .and_then(|info| info.client_cert.as_ref());
+
+        match (self, cert) {
+            (Self::Absent, cert) => cert.is_none(),
+            (_, None) => false,
+            (trigger, Some(cert)) => trigger.matches(cert),
+        }
+    }
+
+    fn matches(&self, cert: &ClientCert) -> bool {
+        match self {
+            Self::Present => true,
+            Self::Absent => false,
+            Self::Subject(reg) => reg.is_match(&cert.subject),
+            Self::San(san) => cert.sans.iter().any(|s| s.eq_ignore_ascii_case(san)),
+            Self::Fingerprint(fingerprints) => fingerprints.contains(&cert.fingerprint),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClientCertTrigger;
+    use crate::conn::{ClientCert, TlsInfo};
+
+    fn mk_req(cert: Option<ClientCert>) -> http::Request<()> {
+        let mut req = http::Request::builder().uri("/").body(()).unwrap();
+        req.extensions_mut().insert(TlsInfo {
+            client_cert: cert,
+            ..TlsInfo::default()
+        });
+
+        req
+    }
+
+    fn svc_a() -> ClientCert {
+        ClientCert {
+            subject: String::from("CN=svc-a, O=Example"),
+            sans: vec![
+                String::from("svc-a.internal"),
+                String::from("spiffe://example/a"),
+            ],
+            fingerprint: String::from("ab12"),
+        }
+    }
+
+    #[test]
+    fn presence_applies() {
+        let with = mk_req(Some(svc_a()));
+        let without = mk_req(None);
+
+        assert!(
+            ClientCertTrigger::Present.applies(&with)
+                && !ClientCertTrigger::Present.applies(&without),
+            "ClientCertTrigger::Present applies only with a certificate"
+        );
+        assert!(
+            ClientCertTrigger::Absent.applies(&without)
+                && !ClientCertTrigger::Absent.applies(&with),
+            "ClientCertTrigger::Absent applies only without a certificate"
+        );
+        assert!(
+            ClientCertTrigger::Absent.applies(&http::Request::new(())),
+            "Requests not received over TLS have no certificate"
+        );
+    }
+
+    #[test]
+    fn identity_applies() {
+        let req = mk_req(Some(svc_a()));
+
+        let subject = ClientCertTrigger::Subject(regex::Regex::new("^CN=svc-a,").unwrap());
+        assert!(
+            subject.applies(&req),
+            "ClientCertTrigger::Subject matches the subject"
+        );
+
+        let san = ClientCertTrigger::San(String::from("SVC-A.internal"));
+        assert!(san.applies(&req), "ClientCertTrigger::San ignores case");
+
+        let san = ClientCertTrigger::San(String::from("svc-b.internal"));
+        assert!(
+            !san.applies(&req),
+            "ClientCertTrigger::San needs an equal name"
+        );
+
+        let fingerprint =
+            ClientCertTrigger::Fingerprint(vec![String::from("cd34"), String::from("ab12")]);
+        assert!(
+            fingerprint.applies(&req),
+            "ClientCertTrigger::Fingerprint applies to any listed fingerprint"
+        );
+        assert!(
+            !fingerprint.applies(&mk_req(None)),
+            "Identity triggers need a certificate"
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use http::header::HeaderName;
+use http::Request;
+
+pub enum HeaderTrigger {
+    Present(HeaderName),
+    Absent(HeaderName),
+    Exactly(HeaderName, String),
+    Contains(HeaderName, String),
+    Regex(HeaderName, regex::Regex),
+    OneOf(HeaderName, Vec<String>),
+}
+
+impl HeaderTrigger {
+    pub fn applies<T>(&self, req: &Request<T>) -> bool {
+        match self {
+            Self::Present(name) => req.headers().contains_key(name),
+            Self::Absent(name) => !req.headers().contains_key(name),
+            Self::Exactly(name, value) => any_value(req, name, |v| v == value),
+            Self::Contains(name, value) => any_value(req, name, |v| v.contains(value.as_str())),
+            Self::Regex(name, reg) => any_value(req, name, |v| reg.is_match(v)),
+            Self::OneOf(name, values) => any_value(req, name, |v| values.iter().any(|x| x == v)),
+        }
+    }
+}
+
+fn any_value<T, F>(req: &Request<T>, name: &HeaderName, pred: F) -> bool
+where
+    F: Fn(&str) -> bool,
+{
+    req.headers()
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(pred)
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::HeaderName;
+
+    fn mk_req(headers: &[(&str, &str)]) -> http::Request<()> {
+        let mut builder = http::Request::builder().uri("http://foo.com/");
+
+        for (k, v) in headers {
+            builder = builder.header(*k, *v);
+        }
+
+        builder.body(()).unwrap()
+    }
+
+    fn tenant() -> HeaderName {
+        HeaderName::from_static("x-tenant")
+    }
+
+    #[test]
+    fn present_applies() {
+        let req_y = mk_req(&[("X-Tenant", "acme")]);
+        let req_n = mk_req(&[]);
+
+        let trigger = super::HeaderTrigger::Present(tenant());
+        assert!(
+            trigger.applies(&req_y),
+            "HeaderTrigger::Present applies only when present"
+        );
+        assert!(
+            !trigger.applies(&req_n),
+            "HeaderTrigger::Present applies only when present"
+        );
+    }
+
+    #[test]
+    fn absent_applies() {
+        let req_y = mk_req(&[]);
+        let req_n = mk_req(&[("X-Tenant", "acme")]);
+
+        let trigger = super::HeaderTrigger::Absent(tenant());
+        assert!(
+            trigger.applies(&req_y),
+            "HeaderTrigger::Absent applies only when absent"
+        );
+        assert!(
+            !trigger.applies(&req_n),
+            "HeaderTrigger::Absent applies only when absent"
+        );
+    }
+
+    #[test]
+    fn exactly_applies() {
+        let req = mk_req(&[("X-Tenant", "acme"), ("X-Tenant", "globex")]);
+
+        let res_y = super::HeaderTrigger::Exactly(tenant(), String::from("globex")).applies(&req);
+        assert!(
+            res_y,
+            "HeaderTrigger::Exactly applies when any value matches exactly"
+        );
+
+        let res_n = super::HeaderTrigger::Exactly(tenant(), String::from("acm")).applies(&req);
+        assert!(
+            !res_n,
+            "HeaderTrigger::Exactly applies only to exact matches"
+        );
+    }
+
+    #[test]
+    fn contains_applies() {
+        let req = mk_req(&[("Accept-Version", "v2-beta")]);
+        let name = HeaderName::from_static("accept-version");
+
+        let res_y = super::HeaderTrigger::Contains(name.clone(), String::from("v2")).applies(&req);
+        assert!(res_y, "HeaderTrigger::Contains applies only to contained");
+
+        let res_n = super::HeaderTrigger::Contains(name, String::from("v3")).applies(&req);
+        assert!(!res_n, "HeaderTrigger::Contains applies only to contained");
+    }
+
+    #[test]
+    fn regex_applies() {
+        let req = mk_req(&[("Accept-Version", "v2")]);
+        let name = HeaderName::from_static("accept-version");
+
+        let reg_y = regex::Regex::new("^v[0-9]+$").unwrap();
+        let res_y = super::HeaderTrigger::Regex(name.clone(), reg_y).applies(&req);
+        assert!(res_y, "HeaderTrigger::Regex applies only to matching");
+
+        let reg_n = regex::Regex::new("^v1$").unwrap();
+        let res_n = super::HeaderTrigger::Regex(name, reg_n).applies(&req);
+        assert!(!res_n, "HeaderTrigger::Regex applies only to matching");
+    }
+
+    #[test]
+    fn one_of_applies() {
+        let req = mk_req(&[("X-Tenant", "acme")]);
+
+        let vx_y = vec![String::from("globex"), String::from("acme")];
+        let res_y = super::HeaderTrigger::OneOf(tenant(), vx_y).applies(&req);
+        assert!(res_y, "HeaderTrigger::OneOf applies only to listed values");
+
+        let vx_n = vec![String::from("globex")];
+        let res_n = super::HeaderTrigger::OneOf(tenant(), vx_n).applies(&req);
+        assert!(!res_n, "HeaderTrigger::OneOf applies only to listed values");
+
+        let res_absent = super::HeaderTrigger::OneOf(tenant(), vec![]).applies(&mk_req(&[]));
+        assert!(
+            !res_absent,
+            "HeaderTrigger::OneOf does not apply when absent"
+        );
+    }
+}
@@ -0,0 +1,214 @@
+use crate::conn::client_scheme;
+use http::header::HOST;
+use http::uri::Authority;
+use http::Request;
+use std::str::FromStr;
+
+pub enum HostPattern {
+    Any,
+    Exactly(String),
+    Wildcard(String),
+    Regex(regex::Regex),
+}
+
+impl HostPattern {
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Exactly(h) => host.eq_ignore_ascii_case(h),
+            Self::Wildcard(w) => {
+                let suffix = w.strip_prefix('*').unwrap_or(w).to_ascii_lowercase();
+                host.len() > suffix.len() && host.to_ascii_lowercase().ends_with(&suffix)
+            }
+            Self::Regex(reg) => reg.is_match(&host.to_ascii_lowercase()),
+        }
+    }
+}
+
+pub struct HostTrigger {
+    pattern: HostPattern,
+    port: Option<u16>,
+}
+
+impl HostTrigger {
+    pub fn new(pattern: HostPattern) -> Self {
+        Self {
+            pattern,
+            port: None,
+        }
+    }
+
+    pub fn any() -> Self {
+        Self::new(HostPattern::Any)
+    }
+
+    pub fn port(self, port: u16) -> Self {
+        Self {
+            port: Some(port),
+            ..self
+        }
+    }
+
+    pub fn applies<T>(&self, req: &Request<T>) -> bool {
+        if let (HostPattern::Any, None) = (&self.pattern, self.port) {
+            return true;
+        }
+
+        let authority = match authority(req) {
+            Some(a) => a,
+            None => return false,
+        };
+
+        let port_applies = match self.port {
+            Some(p) => effective_port(req, &authority) == p,
+            None => true,
+        };
+
+        port_applies && self.pattern.matches(authority.host())
+    }
+}
+
+/// The authority a request was addressed to: the URI authority for absolute-form requests,
+/// otherwise the `Host` header.
+pub(crate) fn authority<T>(req: &Request<T>) -> Option<Authority> {
+    match req.uri().authority() {
+        Some(a) => Some(a.clone()),
+        None => {
+            let host = req.headers().get(HOST)?.to_str().ok()?;
+            Authority::from_str(host).ok()
+        }
+    }
+}
+
+/// The port a request was addressed to, defaulting to the port for the scheme the client used
+/// to reach warden.
+fn effective_port<T>(req: &Request<T>, authority: &Authority) -> u16 {
+    match authority.port_u16() {
+        Some(p) => p,
+        None if client_scheme(req) == "https" => 443,
+        None => 80,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HostPattern, HostTrigger};
+    use crate::conn::TlsInfo;
+
+    fn mk_req(uri: &str, host: Option<&str>) -> http::Request<()> {
+        let builder = http::Request::builder().uri(uri);
+
+        let builder = match host {
+            Some(h) => builder.header("Host", h),
+            None => builder,
+        };
+
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn any_applies() {
+        let reqs = vec![
+            mk_req("/", None),
+            mk_req("/", Some("foo.com")),
+            mk_req("http://bar.com/", None),
+        ];
+
+        for req in reqs {
+            assert!(
+                HostTrigger::any().applies(&req),
+                "HostPattern::Any applies to any host"
+            );
+        }
+    }
+
+    #[test]
+    fn exactly_applies() {
+        let trigger = HostTrigger::new(HostPattern::Exactly(String::from("foo.com")));
+
+        let res_y = trigger.applies(&mk_req("/", Some("FOO.com")));
+        assert!(
+            res_y,
+            "HostPattern::Exactly applies case-insensitively to the Host header"
+        );
+
+        let res_uri = trigger.applies(&mk_req("http://foo.com/", None));
+        assert!(res_uri, "HostPattern::Exactly applies to the URI authority");
+
+        let res_n = trigger.applies(&mk_req("/", Some("www.foo.com")));
+        assert!(!res_n, "HostPattern::Exactly applies only to exact matches");
+
+        let res_none = trigger.applies(&mk_req("/", None));
+        assert!(
+            !res_none,
+            "HostPattern::Exactly does not apply without a host"
+        );
+    }
+
+    #[test]
+    fn wildcard_applies() {
+        let trigger = HostTrigger::new(HostPattern::Wildcard(String::from("*.foo.com")));
+
+        let res_y = trigger.applies(&mk_req("/", Some("a.foo.com")));
+        assert!(res_y, "HostPattern::Wildcard applies to subdomains");
+
+        let res_deep = trigger.applies(&mk_req("/", Some("a.b.foo.com:8080")));
+        assert!(
+            res_deep,
+            "HostPattern::Wildcard applies to nested subdomains"
+        );
+
+        let res_apex = trigger.applies(&mk_req("/", Some("foo.com")));
+        assert!(
+            !res_apex,
+            "HostPattern::Wildcard does not apply to the apex domain"
+        );
+
+        let res_n = trigger.applies(&mk_req("/", Some("afoo.com")));
+        assert!(!res_n, "HostPattern::Wildcard applies only to subdomains");
+    }
+
+    #[test]
+    fn regex_applies() {
+        let reg = regex::Regex::new("^(api|www)\\.foo\\.com$").unwrap();
+        let trigger = HostTrigger::new(HostPattern::Regex(reg));
+
+        let res_y = trigger.applies(&mk_req("/", Some("api.foo.com")));
+        assert!(res_y, "HostPattern::Regex applies only to matching");
+
+        let res_n = trigger.applies(&mk_req("/", Some("cdn.foo.com")));
+        assert!(!res_n, "HostPattern::Regex applies only to matching");
+    }
+
+    #[test]
+    fn port_applies() {
+        let trigger = HostTrigger::new(HostPattern::Exactly(String::from("foo.com"))).port(8080);
+
+        let res_y = trigger.applies(&mk_req("/", Some("foo.com:8080")));
+        assert!(res_y, "HostTrigger port applies only to matching port");
+
+        let res_n = trigger.applies(&mk_req("/", Some("foo.com:8081")));
+        assert!(!res_n, "HostTrigger port applies only to matching port");
+
+        let default_port = HostTrigger::any().port(80);
+        let res_default = default_port.applies(&mk_req("/", Some("foo.com")));
+        assert!(
+            res_default,
+            "HostTrigger port defaults to the scheme's port"
+        );
+
+        let res_https = default_port.applies(&mk_req("https://foo.com/", None));
+        assert!(!res_https, "HostTrigger port defaults to the scheme's port");
+
+        let mut tls_req = mk_req("/", Some("foo.com"));
+        tls_req.extensions_mut().insert(TlsInfo::default());
+        assert!(
+            HostTrigger::any().port(443).applies(&tls_req),
+            "HostTrigger port defaults to 443 for requests received over TLS"
+        );
+        assert!(
+            !default_port.applies(&tls_req),
+            "HostTrigger port defaults to 443 for requests received over TLS"
+        );
+    }
+}
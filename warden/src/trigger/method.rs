@@ -11,7 +11,7 @@ impl MethodTrigger {
   pub fn applies<T>(&self, req: &Request<T>) -> bool {
       match self {
           Self::Any => true,
-          Self::Exactly(method) => req.method() == &method,
+          Self::Exactly(method) => req.method() == method,
           Self::OneOf(methods) => methods.iter().find(|m| m == req.method()).is_some(),
           Self::NoneOf(methods) => methods.iter().find(|m| m == req.method()).is_none(),
       }
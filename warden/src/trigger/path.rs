@@ -5,6 +5,7 @@ pub enum PathTrigger {
   Exactly(String),
   Contains(String),
   Regex(regex::Regex),
+  Template(PathTemplate),
 }
 
 impl PathTrigger {
@@ -16,10 +17,172 @@ impl PathTrigger {
           Self::Exactly(path) => uri.path() == path.as_str(),
           Self::Contains(path) => uri.path().to_string().contains(path.as_str()),
           Self::Regex(reg) => reg.is_match(uri.path()),
+          Self::Template(template) => template.regex.is_match(uri.path()),
+      }
+  }
+
+  /// Names of the parameters `captures` may return.
+  pub fn capture_names(&self) -> Vec<&str> {
+      match self {
+          Self::Regex(reg) => reg.capture_names().flatten().collect(),
+          Self::Template(template) => template.regex.capture_names().flatten().collect(),
+          _ => Vec::new(),
+      }
+  }
+
+  /// Parameters captured from the path by a template or by named regex groups, if the trigger
+  /// applies.
+  pub fn captures<T>(&self, req: &Request<T>) -> Option<PathParams> {
+      match self {
+          Self::Regex(reg) => named_captures(reg, req.uri().path()),
+          Self::Template(template) => named_captures(&template.regex, req.uri().path()),
+          _ if self.applies(req) => Some(PathParams::default()),
+          _ => None,
       }
   }
 }
 
+fn named_captures(reg: &regex::Regex, path: &str) -> Option<PathParams> {
+    let caps = reg.captures(path)?;
+
+    let params = reg
+        .capture_names()
+        .flatten()
+        .filter_map(|name| Some((name.to_string(), caps.name(name)?.as_str().to_string())))
+        .collect();
+
+    Some(PathParams(params))
+}
+
+/// A route template such as `/users/{id}/orders/{order}`, where each `{name}` matches a single
+/// non-empty path segment.
+pub struct PathTemplate {
+    template: String,
+    regex: regex::Regex,
+}
+
+impl PathTemplate {
+    pub fn new(template: &str) -> Result<Self, regex::Error> {
+        let mut pattern = String::from("^");
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}').map(|e| start + e).ok_or_else(|| {
+                regex::Error::Syntax(format!("unclosed parameter in {}", template))
+            })?;
+
+            pattern.push_str(&regex::escape(&rest[..start]));
+            pattern.push_str(&format!("(?P<{}>[^/]+)", &rest[start + 1..end]));
+            rest = &rest[end + 1..];
+        }
+
+        pattern.push_str(&regex::escape(rest));
+        pattern.push('$');
+
+        Ok(Self {
+            template: template.to_string(),
+            regex: regex::Regex::new(&pattern)?,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.template
+    }
+}
+
+/// Values captured by the matching rule's trigger, carried in the request extensions so that
+/// actions can refer to them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PathParams(Vec<(String, String)>);
+
+impl PathParams {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Combine with parameters captured elsewhere, keeping existing values on conflict.
+    pub fn merge(mut self, other: PathParams) -> Self {
+        for (k, v) in other.0 {
+            if self.get(&k).is_none() {
+                self.0.push((k, v));
+            }
+        }
+
+        self
+    }
+
+    /// Substitute `{name}` placeholders in `template`; `{{` and `}}` are literal braces. Unknown
+    /// names expand to nothing, though templates in the ruleset are checked against
+    /// `placeholders` when it is loaded.
+    pub fn expand(&self, template: &str) -> String {
+        pieces(template)
+            .into_iter()
+            .map(|piece| match piece {
+                Piece::Text(text) => text,
+                Piece::Param(name) => self.get(name).unwrap_or_default(),
+            })
+            .collect()
+    }
+}
+
+/// The names of the `{name}` placeholders in a template.
+pub fn placeholders(template: &str) -> Vec<&str> {
+    pieces(template)
+        .into_iter()
+        .filter_map(|piece| match piece {
+            Piece::Param(name) => Some(name),
+            Piece::Text(_) => None,
+        })
+        .collect()
+}
+
+enum Piece<'a> {
+    Text(&'a str),
+    Param(&'a str),
+}
+
+/// Split a template into text and placeholders. A brace that is neither doubled nor part of a
+/// placeholder is kept as text.
+fn pieces(template: &str) -> Vec<Piece<'_>> {
+    let mut pieces = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find(['{', '}']) {
+        pieces.push(Piece::Text(&rest[..start]));
+        let brace = &rest[start..start + 1];
+        let after = &rest[start + 1..];
+
+        if let Some(after) = after.strip_prefix(brace) {
+            pieces.push(Piece::Text(brace));
+            rest = after;
+            continue;
+        }
+
+        match after.find('}').filter(|_| brace == "{") {
+            Some(end) => {
+                pieces.push(Piece::Param(&after[..end]));
+                rest = &after[end + 1..];
+            }
+            None => {
+                pieces.push(Piece::Text(brace));
+                rest = after;
+            }
+        }
+    }
+
+    pieces.push(Piece::Text(rest));
+    pieces
+}
+
+impl FromIterator<(String, String)> for PathParams {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -96,5 +259,98 @@ mod tests {
         let res_n = super::super::PathTrigger::Regex(reg_n).applies(&req);
         assert!(!res_n, "PathTrigger::Regex applies only to matching");
     }
-}
 
+    #[test]
+    fn template_applies() {
+        let template = super::PathTemplate::new("/users/{id}/orders/{order}").unwrap();
+        let trigger = super::PathTrigger::Template(template);
+
+        let req_y = http::Request::builder()
+            .uri("http://foo.com/users/7/orders/42")
+            .body(())
+            .unwrap();
+
+        let params = trigger.captures(&req_y).unwrap();
+        assert_eq!(
+            params.get("id"),
+            Some("7"),
+            "PathTrigger::Template captures parameters"
+        );
+        assert_eq!(
+            params.get("order"),
+            Some("42"),
+            "PathTrigger::Template captures parameters"
+        );
+
+        let req_n = http::Request::builder()
+            .uri("http://foo.com/users/7/orders/42/items")
+            .body(())
+            .unwrap();
+
+        assert!(
+            !trigger.applies(&req_n),
+            "PathTrigger::Template applies only to matching"
+        );
+        assert!(
+            trigger.captures(&req_n).is_none(),
+            "PathTrigger::Template applies only to matching"
+        );
+
+        let req_empty = http::Request::builder()
+            .uri("http://foo.com/users//orders/42")
+            .body(())
+            .unwrap();
+
+        assert!(
+            !trigger.applies(&req_empty),
+            "PathTrigger::Template parameters are non-empty"
+        );
+    }
+
+    #[test]
+    fn regex_captures() {
+        let reg = regex::Regex::new("^/files/(?P<name>[a-z]+)\\.(txt|md)$").unwrap();
+        let trigger = super::PathTrigger::Regex(reg);
+
+        let req = http::Request::builder()
+            .uri("http://foo.com/files/notes.md")
+            .body(())
+            .unwrap();
+
+        let params = trigger.captures(&req).unwrap();
+        assert_eq!(
+            params.get("name"),
+            Some("notes"),
+            "PathTrigger::Regex captures named groups"
+        );
+        assert_eq!(
+            params,
+            super::PathParams::from_iter(vec![(String::from("name"), String::from("notes"))]),
+            "PathTrigger::Regex captures only named groups"
+        );
+    }
+
+    #[test]
+    fn params_expand() {
+        let params = super::PathParams::from_iter(vec![
+            (String::from("id"), String::from("7")),
+            (String::from("order"), String::from("42")),
+        ]);
+
+        assert_eq!(
+            params.expand("/v2/orders/{order}?user={id}&x={missing}"),
+            "/v2/orders/42?user=7&x=",
+            "PathParams::expand substitutes known parameters"
+        );
+        assert_eq!(
+            params.expand(r#"{{"id": {id}}} {"#),
+            r#"{"id": 7} {"#,
+            "PathParams::expand keeps doubled and unmatched braces as literals"
+        );
+        assert_eq!(
+            super::placeholders("{{literal}} {id}/{order}"),
+            vec!["id", "order"],
+            "placeholders lists only the names of placeholders"
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use http::Request;
+
+pub enum QueryTrigger {
+    Any,
+    Present(String),
+    Absent(String),
+    Exactly(String, String),
+    Regex(String, regex::Regex),
+    All(Vec<QueryTrigger>),
+}
+
+impl QueryTrigger {
+    pub fn applies<T>(&self, req: &Request<T>) -> bool {
+        // Parameters are parsed the same way QueryUpdate parses them for rewriting.
+        let params = req
+            .uri()
+            .query()
+            .map(querystring::querify)
+            .unwrap_or_default();
+
+        self.applies_params(&params)
+    }
+
+    fn applies_params(&self, params: &[(&str, &str)]) -> bool {
+        let values = |name: &str| {
+            params
+                .iter()
+                .filter(|(k, _)| *k == name)
+                .map(|(_, v)| *v)
+                .collect::<Vec<_>>()
+        };
+
+        match self {
+            Self::Any => true,
+            Self::Present(name) => !values(name).is_empty(),
+            Self::Absent(name) => values(name).is_empty(),
+            Self::Exactly(name, value) => values(name).iter().any(|v| v == value),
+            Self::Regex(name, reg) => values(name).iter().any(|v| reg.is_match(v)),
+            Self::All(triggers) => triggers.iter().all(|t| t.applies_params(params)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QueryTrigger;
+
+    fn mk_req(uri: &str) -> http::Request<()> {
+        http::Request::builder().uri(uri).body(()).unwrap()
+    }
+
+    #[test]
+    fn any_applies() {
+        let uris = vec!["/", "/?", "/?a=b"];
+
+        for uri in uris {
+            let res = QueryTrigger::Any.applies(&mk_req(uri));
+            assert!(res, "QueryTrigger::Any applies to any query");
+        }
+    }
+
+    #[test]
+    fn present_applies() {
+        let trigger = QueryTrigger::Present(String::from("debug"));
+
+        let res_y = trigger.applies(&mk_req("/?a=b&debug=1"));
+        assert!(res_y, "QueryTrigger::Present applies only when present");
+
+        let res_n = trigger.applies(&mk_req("/?a=b"));
+        assert!(!res_n, "QueryTrigger::Present applies only when present");
+
+        let res_none = trigger.applies(&mk_req("/"));
+        assert!(!res_none, "QueryTrigger::Present applies only when present");
+    }
+
+    #[test]
+    fn absent_applies() {
+        let trigger = QueryTrigger::Absent(String::from("debug"));
+
+        let res_y = trigger.applies(&mk_req("/"));
+        assert!(res_y, "QueryTrigger::Absent applies only when absent");
+
+        let res_n = trigger.applies(&mk_req("/?debug=1"));
+        assert!(!res_n, "QueryTrigger::Absent applies only when absent");
+    }
+
+    #[test]
+    fn exactly_applies() {
+        let trigger = QueryTrigger::Exactly(String::from("v"), String::from("2"));
+
+        let res_y = trigger.applies(&mk_req("/?v=1&v=2"));
+        assert!(
+            res_y,
+            "QueryTrigger::Exactly applies when any value matches exactly"
+        );
+
+        let res_n = trigger.applies(&mk_req("/?v=22"));
+        assert!(
+            !res_n,
+            "QueryTrigger::Exactly applies only to exact matches"
+        );
+    }
+
+    #[test]
+    fn regex_applies() {
+        let reg = regex::Regex::new("^[0-9]+$").unwrap();
+        let trigger = QueryTrigger::Regex(String::from("id"), reg);
+
+        let res_y = trigger.applies(&mk_req("/?id=42"));
+        assert!(res_y, "QueryTrigger::Regex applies only to matching");
+
+        let res_n = trigger.applies(&mk_req("/?id=abc"));
+        assert!(!res_n, "QueryTrigger::Regex applies only to matching");
+    }
+
+    #[test]
+    fn all_applies() {
+        let trigger = QueryTrigger::All(vec![
+            QueryTrigger::Exactly(String::from("a"), String::from("b")),
+            QueryTrigger::Present(String::from("c")),
+        ]);
+
+        let res_y = trigger.applies(&mk_req("/?c=d&a=b"));
+        assert!(
+            res_y,
+            "QueryTrigger::All applies only when every trigger applies"
+        );
+
+        let res_n = trigger.applies(&mk_req("/?a=b"));
+        assert!(
+            !res_n,
+            "QueryTrigger::All applies only when every trigger applies"
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Call `reload` on SIGHUP and, when `poll` is set, whenever the modification time of any of
+/// `paths` changes. `reload` is given the reason: "SIGHUP", or `changed`.
+pub fn spawn<F>(paths: Vec<PathBuf>, poll: Option<Duration>, changed: &'static str, reload: F)
+where
+    F: Fn(&str) + Send + Sync + 'static,
+{
+    let reload = Arc::new(reload);
+
+    tokio::spawn(reload_on_sighup(reload.clone()));
+
+    if let Some(interval) = poll {
+        tokio::spawn(reload_on_change(reload, paths, interval, changed));
+    }
+}
+
+async fn reload_on_sighup<F: Fn(&str)>(reload: Arc<F>) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(err) => {
+            tracing::error!("cannot listen for SIGHUP: {}", err);
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        reload("SIGHUP");
+    }
+}
+
+async fn reload_on_change<F: Fn(&str)>(
+    reload: Arc<F>,
+    paths: Vec<PathBuf>,
+    interval: Duration,
+    changed: &str,
+) {
+    let mut last_modified = modified(&paths);
+    let mut ticks = tokio::time::interval(interval);
+
+    loop {
+        ticks.tick().await;
+
+        let current = modified(&paths);
+        if current != last_modified {
+            last_modified = current;
+            reload(changed);
+        }
+    }
+}
+
+fn modified(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}